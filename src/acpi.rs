@@ -0,0 +1,265 @@
+use alloc::vec::Vec;
+use core::ptr;
+
+// NOTE: just enough ACPI to find the interrupt controllers
+//
+// The RSDP is found by scanning the BIOS areas, it points to the RSDT (32-bit table pointers)
+// or, from ACPI 2.0 on, to the XSDT (64-bit table pointers). Among those tables the MADT
+// (signature "APIC") lists the local APICs, the IO-APICs and how legacy IRQs are wired.
+// All physical memory is mapped at `PHYSICAL_MEMORY_OFFSET`, so tables are read in place.
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+const HPET_SIGNATURE: &[u8; 4] = b"HPET";
+const SDT_HEADER_SIZE: usize = 36;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: u32,
+    // the first global system interrupt handled by this IO-APIC
+    pub gsi_base: u32,
+}
+
+// a legacy ISA IRQ that is not identity mapped to a global system interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    // MPS INTI flags: bits 0-1 polarity, bits 2-3 trigger mode
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    pub fn active_low(&self) -> bool {
+        self.flags & 0x3 == 0x3
+    }
+
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0x3 == 0x3
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    // the APIC ids of the enabled processors
+    pub local_apic_ids: Vec<u8>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+    // bit 0 of the MADT flags: the system also has dual 8259 PICs
+    pub has_legacy_pics: bool,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from(bytes[offset]) | u16::from(bytes[offset + 1]) << 8
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from(read_u16(bytes, offset)) | u32::from(read_u16(bytes, offset + 2)) << 16
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from(read_u32(bytes, offset)) | u64::from(read_u32(bytes, offset + 4)) << 32
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+impl Madt {
+    // parse a complete MADT, header included
+    pub fn parse(bytes: &[u8]) -> Option<Madt> {
+        if bytes.len() < SDT_HEADER_SIZE + 8 || &bytes[0..4] != MADT_SIGNATURE {
+            return None;
+        }
+        let mut madt = Madt {
+            local_apic_address: u64::from(read_u32(bytes, 36)),
+            local_apic_ids: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            has_legacy_pics: read_u32(bytes, 40) & 0x1 != 0,
+        };
+
+        let mut offset = SDT_HEADER_SIZE + 8;
+        while offset + 2 <= bytes.len() {
+            let entry_type = bytes[offset];
+            let length = bytes[offset + 1] as usize;
+            if length < 2 || offset + length > bytes.len() {
+                break;
+            }
+            let entry = &bytes[offset..offset + length];
+            match (entry_type, length) {
+                // processor local APIC, bit 0 of the flags: enabled
+                (0, 8) => {
+                    if read_u32(entry, 4) & 0x1 != 0 {
+                        madt.local_apic_ids.push(entry[3]);
+                    }
+                }
+                (1, 12) => madt.io_apics.push(IoApicInfo {
+                    id: entry[2],
+                    address: read_u32(entry, 4),
+                    gsi_base: read_u32(entry, 8),
+                }),
+                (2, 10) => madt.overrides.push(InterruptOverride {
+                    irq: entry[3],
+                    gsi: read_u32(entry, 4),
+                    flags: read_u16(entry, 8),
+                }),
+                // local APIC address override
+                (5, 12) => madt.local_apic_address = read_u64(entry, 4),
+                _ => (),
+            }
+            offset += length;
+        }
+        Some(madt)
+    }
+
+    // the global system interrupt and the override (if any) of a legacy IRQ
+    pub fn legacy_irq(&self, irq: u8) -> (u32, Option<InterruptOverride>) {
+        match self.overrides.iter().find(|o| o.irq == irq) {
+            Some(o) => (o.gsi, Some(*o)),
+            None => (u32::from(irq), None),
+        }
+    }
+}
+
+// a slice of physical memory through the complete physical memory mapping
+unsafe fn phys_slice(addr: u64, len: usize) -> &'static [u8] {
+    let physical_memory_offset = crate::PHYSICAL_MEMORY_OFFSET as u64;
+    core::slice::from_raw_parts((addr + physical_memory_offset) as *const u8, len)
+}
+
+// the RSDP is 16 byte aligned, either in the first KiB of the EBDA or in 0xE0000..0x100000
+fn find_rsdp() -> Option<u64> {
+    // the BIOS data area holds the real mode segment of the EBDA
+    let ebda_segment = unsafe { ptr::read_unaligned(phys_slice(0x40e, 2).as_ptr() as *const u16) };
+    let ebda = u64::from(ebda_segment) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe0000, 0x100000)];
+    for &(start, end) in areas.iter() {
+        if start == 0 {
+            continue;
+        }
+        let mut addr = start;
+        while addr + 20 <= end {
+            let candidate = unsafe { phys_slice(addr, 20) };
+            if &candidate[0..8] == RSDP_SIGNATURE && checksum_ok(candidate) {
+                return Some(addr);
+            }
+            addr += 16;
+        }
+    }
+    None
+}
+
+// the full table at `addr`, its length comes from the header
+unsafe fn sdt_at(addr: u64) -> &'static [u8] {
+    let length = read_u32(phys_slice(addr, SDT_HEADER_SIZE), 4) as usize;
+    phys_slice(addr, length)
+}
+
+// the physical address of the table with the given signature
+pub fn find_table(signature: &[u8; 4]) -> Option<u64> {
+    let rsdp_addr = find_rsdp()?;
+    let rsdp = unsafe { phys_slice(rsdp_addr, 36) };
+    let revision = rsdp[15];
+    let (root_addr, pointer_size) = if revision >= 2 {
+        (read_u64(rsdp, 24), 8)
+    } else {
+        (u64::from(read_u32(rsdp, 16)), 4)
+    };
+
+    let root = unsafe { sdt_at(root_addr) };
+    if !checksum_ok(root) {
+        return None;
+    }
+    let mut offset = SDT_HEADER_SIZE;
+    while offset + pointer_size <= root.len() {
+        let table_addr = if pointer_size == 8 {
+            read_u64(root, offset)
+        } else {
+            u64::from(read_u32(root, offset))
+        };
+        let header = unsafe { phys_slice(table_addr, SDT_HEADER_SIZE) };
+        if &header[0..4] == signature {
+            return Some(table_addr);
+        }
+        offset += pointer_size;
+    }
+    None
+}
+
+pub fn find_madt() -> Option<Madt> {
+    let madt = unsafe { sdt_at(find_table(MADT_SIGNATURE)?) };
+    if !checksum_ok(madt) {
+        return None;
+    }
+    Madt::parse(madt)
+}
+
+// the physical address of the HPET registers
+pub fn find_hpet() -> Option<u64> {
+    let hpet = unsafe { sdt_at(find_table(HPET_SIGNATURE)?) };
+    // the base address is a generic address structure, address space 0 is memory
+    if !checksum_ok(hpet) || hpet.len() < 52 || hpet[40] != 0 {
+        return None;
+    }
+    Some(read_u64(hpet, 44))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn madt_bytes(entries: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = vec![0u8; SDT_HEADER_SIZE + 8];
+        bytes[0..4].copy_from_slice(MADT_SIGNATURE);
+        // local APIC address and flags
+        bytes[36..40].copy_from_slice(&[0x00, 0x00, 0xe0, 0xfe]);
+        bytes[40] = 0x1;
+        for entry in entries {
+            bytes.extend_from_slice(entry);
+        }
+        let length = bytes.len() as u32;
+        bytes[4..8].copy_from_slice(&length.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn parse_madt() {
+        let bytes = madt_bytes(&[
+            // enabled and disabled processors
+            &[0, 8, 0, 0, 1, 0, 0, 0],
+            &[0, 8, 1, 1, 0, 0, 0, 0],
+            // IO-APIC 0 at 0xfec00000, GSI base 0
+            &[1, 12, 0, 0, 0x00, 0x00, 0xc0, 0xfe, 0, 0, 0, 0],
+            // IRQ 0 -> GSI 2, IRQ 9 -> GSI 9 level triggered, active low
+            &[2, 10, 0, 0, 2, 0, 0, 0, 0, 0],
+            &[2, 10, 0, 9, 9, 0, 0, 0, 0x0f, 0],
+        ]);
+        let madt = Madt::parse(&bytes).unwrap();
+        assert_eq!(madt.local_apic_address, 0xfee0_0000);
+        assert!(madt.has_legacy_pics);
+        assert_eq!(madt.local_apic_ids, vec![0]);
+        assert_eq!(
+            madt.io_apics,
+            vec![IoApicInfo {
+                id: 0,
+                address: 0xfec0_0000,
+                gsi_base: 0
+            }]
+        );
+        assert_eq!(madt.legacy_irq(0).0, 2);
+        assert_eq!(madt.legacy_irq(1), (1, None));
+        let (gsi, sci) = madt.legacy_irq(9);
+        assert_eq!(gsi, 9);
+        assert!(sci.unwrap().active_low() && sci.unwrap().level_triggered());
+    }
+
+    #[test]
+    fn reject_other_tables() {
+        let mut bytes = madt_bytes(&[]);
+        bytes[0..4].copy_from_slice(b"FACP");
+        assert!(Madt::parse(&bytes).is_none());
+    }
+}
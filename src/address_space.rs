@@ -0,0 +1,491 @@
+use crate::frame_allocator::get_frame_allocator;
+use crate::memory::phys2virt;
+use crate::PHYSICAL_MEMORY_OFFSET;
+
+use core::ptr;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Page, PageSize, PageTable, PageTableFlags, PhysFrame,
+    Size4KiB,
+};
+use x86_64::VirtAddr;
+
+// NOTE: bit 9 of a page table entry is ignored by the CPU
+// we use it to mark a page as copy-on-write
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+// and bit 10 to mark a page of a shared memory object, which is never copied on write
+pub const SHARED: PageTableFlags = PageTableFlags::BIT_10;
+// bit 11 marks a non-present entry whose page is swapped out,
+// the address bits of such an entry hold the swap slot (see `swap`)
+pub const SWAPPED: PageTableFlags = PageTableFlags::BIT_11;
+
+// NOTE: layout of a process address space
+//
+// L4 entries [256, 512) (the upper half) belong to the kernel and are shared by every process.
+// The lower half is private to each process and starts out empty.
+// bootloader 0.6 still places the kernel image, the boot stack and the boot info in the
+// lower half, so the lower L4 slots in use at boot are recorded and shared as kernel slots too.
+// It also leaves the temporary page it zeroes .bss through mapped, nothing refers to it once
+// the kernel runs. Its L4 slot lies inside the user range and is dropped from the kernel table.
+pub const KERNEL_L4_START: usize = 256;
+const BOOTLOADER_TEMP_PAGE: u64 = 0x_0000_0fee_efee_e000;
+pub const USER_SPACE_START: u64 = 0x_0000_0100_0000_0000;
+pub const USER_SPACE_END: u64 = 0x_0000_5000_0000_0000;
+
+static mut BOOT_KERNEL_L4_SLOTS: [bool; KERNEL_L4_START] = [false; KERNEL_L4_START];
+
+// access a page table through the complete physical memory mapping
+pub unsafe fn table_of(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *(frame_ptr(frame) as *mut PageTable)
+}
+
+pub fn frame_ptr(frame: PhysFrame) -> *mut u8 {
+    let physical_memory_offset = unsafe { PHYSICAL_MEMORY_OFFSET };
+    phys2virt(frame.start_address().as_u64() as usize, physical_memory_offset) as *mut u8
+}
+
+pub fn active_l4_frame() -> PhysFrame {
+    let (level_4_table_frame, _) = Cr3::read();
+    level_4_table_frame
+}
+
+pub fn is_kernel_l4_slot(index: usize) -> bool {
+    index >= KERNEL_L4_START || unsafe { BOOT_KERNEL_L4_SLOTS[index] }
+}
+
+// the number of pages mapped below `table` of `level`, a huge page counts as one
+fn mapping_num(table: &PageTable, level: usize) -> usize {
+    let mut num = 0;
+    for entry in table.iter() {
+        if entry.is_unused() {
+            continue;
+        }
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            num += 1;
+        } else if let Ok(frame) = entry.frame() {
+            num += mapping_num(unsafe { table_of(frame) }, level - 1);
+        }
+    }
+    num
+}
+
+// must be called once after the frame allocator is set up,
+// before any process page table is created
+pub fn init() {
+    let kernel_l4 = unsafe { table_of(active_l4_frame()) };
+
+    // the tables below the entry belong to the bootloader and are not handed out, let them leak
+    // nothing but the temp page may go away with them
+    let temp_page = VirtAddr::new(BOOTLOADER_TEMP_PAGE);
+    let temp_slot = &mut kernel_l4[usize::from(temp_page.p4_index())];
+    if let Ok(l3_frame) = temp_slot.frame() {
+        let temp_mapped = leaf_entry(active_l4_frame(), temp_page)
+            .map_or(false, |entry| !entry.is_unused());
+        assert_eq!(
+            mapping_num(unsafe { table_of(l3_frame) }, 3),
+            temp_mapped as usize,
+            "the L4 slot of the bootloader's temp page maps other pages too"
+        );
+    }
+    temp_slot.set_unused();
+
+    // kernel mappings are never user accessible
+    for i in 0..KERNEL_L4_START {
+        let entry = &mut kernel_l4[i];
+        if !entry.is_unused() {
+            unsafe { BOOT_KERNEL_L4_SLOTS[i] = true };
+            let flags = entry.flags();
+            entry.set_flags(flags - PageTableFlags::USER_ACCESSIBLE);
+        }
+    }
+    let user_start = VirtAddr::new(USER_SPACE_START);
+    let user_end = VirtAddr::new(USER_SPACE_END - 1);
+    let user_slots = usize::from(user_start.p4_index())..=usize::from(user_end.p4_index());
+    for i in user_slots {
+        assert!(!is_kernel_l4_slot(i), "user space overlaps a kernel L4 slot");
+    }
+
+    // the upper half L4 entries are copied into every process,
+    // so their L3 tables must exist before the first copy is made.
+    // otherwise later kernel mappings would not show up in existing processes
+    let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for i in KERNEL_L4_START..512 {
+        let entry = &mut kernel_l4[i];
+        if entry.is_unused() {
+            let l3_frame = get_frame_allocator()
+                .alloc_zeroed_frame()
+                .expect("out of memory while allocating kernel L3 tables");
+            entry.set_frame(l3_frame, table_flags);
+        }
+        let flags = entry.flags();
+        entry.set_flags(flags - PageTableFlags::USER_ACCESSIBLE);
+    }
+    crate::tlb::flush_all();
+}
+
+// a fresh level 4 table for a new process:
+// kernel slots are shared with the current table, the user half is empty
+pub fn new_user_l4() -> Option<PhysFrame> {
+    let new_l4_frame = get_frame_allocator().alloc_zeroed_frame()?;
+    let kernel_l4 = unsafe { table_of(active_l4_frame()) };
+    let new_l4 = unsafe { table_of(new_l4_frame) };
+
+    for (i, entry) in kernel_l4.iter().enumerate() {
+        if is_kernel_l4_slot(i) && !entry.is_unused() {
+            new_l4[i].set_addr(entry.addr(), entry.flags() - PageTableFlags::USER_ACCESSIBLE);
+        }
+    }
+    Some(new_l4_frame)
+}
+
+// sign extend bit 47 to get a canonical address
+pub fn canonical(addr: u64) -> u64 {
+    if addr & (1 << 47) != 0 {
+        addr | 0xffff_0000_0000_0000
+    } else {
+        addr
+    }
+}
+
+// apply `update` to every present leaf entry (huge pages included) overlapping [start, end)
+// `update` gets the virtual address of the mapping and its current flags
+// the caller is responsible for flushing the TLB
+pub fn update_leaf_flags<F>(l4_frame: PhysFrame, start: u64, end: u64, update: &mut F)
+where
+    F: FnMut(u64, PageTableFlags) -> PageTableFlags,
+{
+    let l4 = unsafe { table_of(l4_frame) };
+    update_table_flags(l4, 4, 0, start, end, update);
+}
+
+fn update_table_flags<F>(
+    table: &mut PageTable,
+    level: u32,
+    base: u64,
+    start: u64,
+    end: u64,
+    update: &mut F,
+) where
+    F: FnMut(u64, PageTableFlags) -> PageTableFlags,
+{
+    let entry_size: u64 = Size4KiB::SIZE << (9 * (level - 1));
+    for (i, entry) in table.iter_mut().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let virt = canonical(base + i as u64 * entry_size);
+        if virt >= end || virt + (entry_size - 1) < start {
+            continue;
+        }
+        if level == 1 || (level < 4 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+            entry.set_flags(update(virt, flags));
+        } else if let Ok(frame) = entry.frame() {
+            let next = unsafe { table_of(frame) };
+            update_table_flags(next, level - 1, base + i as u64 * entry_size, start, end, update);
+        }
+    }
+}
+
+// walk down to the level 1 entry of `addr`
+// returns None if an intermediate table is missing or `addr` is in a huge page
+pub fn leaf_entry(l4_frame: PhysFrame, addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+    let page: Page<Size4KiB> = Page::containing_address(addr);
+    let indexes = [page.p4_index(), page.p3_index(), page.p2_index()];
+
+    let mut table = unsafe { table_of(l4_frame) };
+    for &index in indexes.iter() {
+        let entry = &table[index];
+        if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = unsafe { table_of(entry.frame().ok()?) };
+    }
+    Some(&mut table[page.p1_index()])
+}
+
+// same as `leaf_entry`, but missing intermediate tables are allocated
+// they get USER_ACCESSIBLE too, otherwise the CPU denies user access to the leaf
+fn create_leaf_entry(
+    l4_frame: PhysFrame,
+    page: Page<Size4KiB>,
+) -> Option<&'static mut PageTableEntry> {
+    if is_kernel_l4_slot(usize::from(page.p4_index())) {
+        return None;
+    }
+    let indexes = [page.p4_index(), page.p3_index(), page.p2_index()];
+    let table_flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+    let mut table = unsafe { table_of(l4_frame) };
+    for &index in indexes.iter() {
+        let entry = &mut table[index];
+        if entry.is_unused() {
+            let new_table_frame = get_frame_allocator().alloc_zeroed_frame()?;
+            entry.set_frame(new_table_frame, table_flags);
+        }
+        let flags = entry.flags();
+        // never hand a kernel subtree to user space
+        if flags.contains(PageTableFlags::HUGE_PAGE)
+            || !flags.contains(PageTableFlags::USER_ACCESSIBLE)
+        {
+            return None;
+        }
+        table = unsafe { table_of(entry.frame().ok()?) };
+    }
+    Some(&mut table[page.p1_index()])
+}
+
+// map a fresh zeroed frame at `page` in the address space rooted at `l4_frame`
+pub fn map_user_page(
+    l4_frame: PhysFrame,
+    page: Page<Size4KiB>,
+    flags: PageTableFlags,
+) -> Option<PhysFrame> {
+    let frame_allocator = get_frame_allocator();
+    let frame = frame_allocator.alloc_zeroed_frame()?;
+    if !map_user_frame(l4_frame, page, frame, flags) {
+        frame_allocator.deallocate_frame(frame);
+        return None;
+    }
+    Some(frame)
+}
+
+// map an existing `frame` at `page`, the frame gets one more reference
+// returns false if `page` is already mapped or a table cannot be allocated
+pub fn map_user_frame(
+    l4_frame: PhysFrame,
+    page: Page<Size4KiB>,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> bool {
+    let entry = match create_leaf_entry(l4_frame, page) {
+        Some(entry) => entry,
+        None => return false,
+    };
+    if !entry.is_unused() {
+        return false;
+    }
+
+    entry.set_frame(
+        frame,
+        flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE,
+    );
+    if let Some(frame_info) = get_frame_allocator().frame_info(frame) {
+        frame_info.inc_count();
+    }
+    if l4_frame == active_l4_frame() {
+        x86_64::instructions::tlb::flush(page.start_address());
+    }
+    true
+}
+
+// unmap the user pages in [start, end), frames nobody maps anymore are freed
+// the page tables stay until the address space is destroyed
+pub fn unmap_user_range(l4_frame: PhysFrame, start: u64, end: u64) {
+    use crate::tlb::TlbBatch;
+
+    let frame_allocator = get_frame_allocator();
+    let mut batch = TlbBatch::for_address_space(l4_frame);
+    let mut addr = start;
+    while addr < end {
+        let page = VirtAddr::new(addr);
+        addr += Size4KiB::SIZE;
+        if is_kernel_l4_slot(usize::from(page.p4_index())) {
+            continue;
+        }
+        let entry = match leaf_entry(l4_frame, page) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        if let Some(slot) = crate::swap::slot_of(entry) {
+            crate::swap::free_slot(slot);
+            entry.set_unused();
+            continue;
+        }
+        let frame = entry.frame();
+        entry.set_unused();
+        if let Ok(frame) = frame {
+            batch.add(page);
+            if let Some(frame_info) = frame_allocator.frame_info(frame) {
+                if frame_info.dec_count() == 0 {
+                    frame_allocator.deallocate_frame(frame);
+                }
+            }
+        }
+    }
+    batch.flush();
+}
+
+// make a copy-on-write clone of the address space rooted at `src_l4_frame`
+//
+// Kernel L4 slots and entries without USER_ACCESSIBLE are shared as they are.
+// Tables leading to user pages are copied, user pages themselves are shared:
+// writable ones become read-only + COW in *both* address spaces, and the
+// refcount in `FrameInfo` records how many entries map each frame.
+pub fn clone_address_space(src_l4_frame: PhysFrame) -> Option<PhysFrame> {
+    let new_l4_frame = clone_table(src_l4_frame, 4)?;
+
+    // the source lost its WRITABLE bits, so stale TLB entries must go
+    crate::tlb::flush_address_space(src_l4_frame);
+    Some(new_l4_frame)
+}
+
+// FIXME: tables copied before an allocation failure are leaked
+fn clone_table(src_frame: PhysFrame, level: usize) -> Option<PhysFrame> {
+    let frame_allocator = get_frame_allocator();
+    let new_frame = frame_allocator.alloc_zeroed_frame()?;
+
+    let src = unsafe { table_of(src_frame) };
+    let dst = unsafe { table_of(new_frame) };
+
+    for (i, entry) in src.iter_mut().enumerate() {
+        if entry.is_unused() {
+            continue;
+        }
+        // both entries refer to the same swap slot, each gets its own copy on swap in
+        if let Some(slot) = crate::swap::slot_of(entry) {
+            crate::swap::dup_slot(slot);
+            dst[i] = entry.clone();
+            continue;
+        }
+        let flags = entry.flags();
+        // kernel mapping, share the whole subtree
+        // FIXME: huge user pages are shared without COW
+        if (level == 4 && is_kernel_l4_slot(i))
+            || !flags.contains(PageTableFlags::USER_ACCESSIBLE)
+            || flags.contains(PageTableFlags::HUGE_PAGE)
+        {
+            dst[i] = entry.clone();
+            continue;
+        }
+
+        if level == 1 {
+            let mut new_flags = flags;
+            // shared memory stays shared
+            if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(SHARED) {
+                new_flags = (flags - PageTableFlags::WRITABLE) | COW;
+                entry.set_flags(new_flags);
+            }
+            dst[i].set_addr(entry.addr(), new_flags);
+            if let Ok(frame) = entry.frame() {
+                if let Some(frame_info) = frame_allocator.frame_info(frame) {
+                    frame_info.inc_count();
+                }
+            }
+        } else {
+            let child_frame = clone_table(entry.frame().ok()?, level - 1)?;
+            dst[i].set_addr(child_frame.start_address(), flags);
+        }
+    }
+    Some(new_frame)
+}
+
+// called by the page fault handler on a write to a present page
+// returns false if the page is not a COW page
+pub fn resolve_cow(addr: VirtAddr) -> bool {
+    let entry = match leaf_entry(active_l4_frame(), addr) {
+        Some(entry) => entry,
+        None => return false,
+    };
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) || !flags.contains(COW) {
+        return false;
+    }
+    let old_frame = match entry.frame() {
+        Ok(frame) => frame,
+        Err(_) => return false,
+    };
+    let new_flags = (flags - COW) | PageTableFlags::WRITABLE;
+
+    let frame_allocator = get_frame_allocator();
+    // frames we don't track are never written in place
+    let shared = match frame_allocator.frame_info(old_frame) {
+        Some(frame_info) => frame_info.get_count() > 1,
+        None => true,
+    };
+
+    if shared {
+        let new_frame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                frame_ptr(old_frame) as *const u8,
+                frame_ptr(new_frame),
+                Size4KiB::SIZE as usize,
+            )
+        };
+        if let Some(frame_info) = frame_allocator.frame_info(old_frame) {
+            frame_info.dec_count();
+        }
+        if let Some(frame_info) = frame_allocator.frame_info(new_frame) {
+            frame_info.inc_count();
+        }
+        entry.set_addr(new_frame.start_address(), new_flags);
+    } else {
+        // the last owner keeps the frame
+        entry.set_flags(new_flags);
+    }
+    x86_64::instructions::tlb::flush(addr);
+    true
+}
+
+// free the address space rooted at `l4_frame`, the reverse of `new_user_l4` and `clone_address_space`
+//
+// The private user half is walked: every mapped frame loses one reference and is freed
+// when nobody maps it anymore, the table frames themselves are freed as well.
+// Kernel slots are shared with every other process and are left alone.
+// `l4_frame` must not be the active page table.
+pub fn destroy_address_space(l4_frame: PhysFrame) {
+    assert!(
+        l4_frame != active_l4_frame(),
+        "destroying the active address space"
+    );
+    let l4 = unsafe { table_of(l4_frame) };
+    for i in 0..KERNEL_L4_START {
+        if is_kernel_l4_slot(i) {
+            continue;
+        }
+        release_entry(&mut l4[i], 4);
+    }
+    get_frame_allocator().deallocate_frame(l4_frame);
+}
+
+fn release_entry(entry: &mut PageTableEntry, level: usize) {
+    if entry.is_unused() {
+        return;
+    }
+    if let Some(slot) = crate::swap::slot_of(entry) {
+        crate::swap::free_slot(slot);
+        entry.set_unused();
+        return;
+    }
+    // FIXME: huge user pages are never created, so they are not freed either
+    // not present entries don't reference a frame we own
+    let frame = entry.frame();
+    entry.set_unused();
+    let frame = match frame {
+        Ok(frame) => frame,
+        Err(_) => return,
+    };
+    let frame_allocator = get_frame_allocator();
+
+    if level == 1 {
+        // frames we don't track (e.g. device memory) are never freed
+        if let Some(frame_info) = frame_allocator.frame_info(frame) {
+            if frame_info.dec_count() == 0 {
+                frame_allocator.deallocate_frame(frame);
+            }
+        }
+        return;
+    }
+
+    let table = unsafe { table_of(frame) };
+    for entry in table.iter_mut() {
+        release_entry(entry, level - 1);
+    }
+    frame_allocator.deallocate_frame(frame);
+}
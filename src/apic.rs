@@ -0,0 +1,247 @@
+use crate::acpi::{self, Madt};
+use crate::vmalloc::ioremap;
+
+use alloc::vec::Vec;
+use core::ptr;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+// NOTE: local APIC and IO-APIC
+//
+// The IO-APICs take the device interrupts and send them as messages to the local APIC of a
+// processor, which raises the vector. Both are found in the ACPI MADT. Once they are set up
+// the 8259 PICs are masked, and every interrupt is acknowledged at the local APIC.
+// Without a MADT (or an APIC) we stay on the PICs.
+//
+// Legacy IRQs are routed to the same vectors the PICs used (IRQ n -> 32 + n), so the IDT
+// does not care which controller is in use. Every redirection entry starts masked, `irq`
+// routes the lines that have handlers.
+
+// CPUID.01H:EDX
+const CPUID_APIC: u32 = 1 << 9;
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x_000f_ffff_ffff_f000;
+
+// local APIC registers, offsets from the base
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SVR: usize = 0xf0;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_LVT_LINT0: usize = 0x350;
+const LAPIC_LVT_LINT1: usize = 0x360;
+const LAPIC_LVT_ERROR: usize = 0x370;
+const LAPIC_SIZE: usize = 0x400;
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+// the vector of spurious interrupts, they are not acknowledged
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// IO-APIC registers are accessed indirectly through IOREGSEL and IOWIN
+const IOAPIC_IOREGSEL: usize = 0x00;
+const IOAPIC_IOWIN: usize = 0x10;
+const IOAPIC_SIZE: usize = 0x20;
+const IOAPIC_VER: u32 = 0x01;
+const IOAPIC_REDTBL: u32 = 0x10;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_DATA: u16 = 0xa1;
+
+// the mapped local APIC registers, 0 while the PICs are in use
+// read by every interrupt handler, so it is not behind a lock
+static mut LAPIC_BASE: u64 = 0;
+
+struct IoApic {
+    base: u64,
+    gsi_base: u32,
+    // number of redirection entries
+    entry_num: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, reg: u32) -> u32 {
+        ptr::write_volatile((self.base as usize + IOAPIC_IOREGSEL) as *mut u32, reg);
+        ptr::read_volatile((self.base as usize + IOAPIC_IOWIN) as *const u32)
+    }
+
+    unsafe fn write(&self, reg: u32, value: u32) {
+        ptr::write_volatile((self.base as usize + IOAPIC_IOREGSEL) as *mut u32, reg);
+        ptr::write_volatile((self.base as usize + IOAPIC_IOWIN) as *mut u32, value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entry_num
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let reg = IOAPIC_REDTBL + 2 * (gsi - self.gsi_base);
+        unsafe {
+            // masked while the two halves disagree
+            self.write(reg, REDIRECTION_MASKED as u32);
+            self.write(reg + 1, (entry >> 32) as u32);
+            self.write(reg, entry as u32);
+        }
+    }
+}
+
+struct InterruptControllers {
+    io_apics: Vec<IoApic>,
+    madt: Madt,
+    lapic_id: u8,
+}
+
+impl InterruptControllers {
+    fn io_apic_of(&self, gsi: u32) -> Option<&IoApic> {
+        self.io_apics.iter().find(|io_apic| io_apic.handles(gsi))
+    }
+}
+
+lazy_static! {
+    static ref CONTROLLERS: Mutex<Option<InterruptControllers>> = Mutex::new(None);
+}
+
+unsafe fn lapic_read(reg: usize) -> u32 {
+    ptr::read_volatile((LAPIC_BASE as usize + reg) as *const u32)
+}
+
+unsafe fn lapic_write(reg: usize, value: u32) {
+    ptr::write_volatile((LAPIC_BASE as usize + reg) as *mut u32, value);
+}
+
+pub fn is_enabled() -> bool {
+    unsafe { LAPIC_BASE != 0 }
+}
+
+// acknowledge the interrupt being handled, only valid if the APIC is enabled
+pub fn end_of_interrupt() {
+    unsafe { lapic_write(LAPIC_EOI, 0) };
+}
+
+pub fn local_apic_id() -> Option<u8> {
+    if !is_enabled() {
+        return None;
+    }
+    Some((unsafe { lapic_read(LAPIC_ID) } >> 24) as u8)
+}
+
+fn mask_pics() {
+    let pic_1: Port<u8> = Port::new(PIC_1_DATA);
+    let pic_2: Port<u8> = Port::new(PIC_2_DATA);
+    unsafe {
+        pic_1.write(0xff);
+        pic_2.write(0xff);
+    }
+}
+
+// switch from the PICs to the APICs, returns false (and keeps the PICs) if there are none
+// needs the heap and the vmalloc area, interrupts must be disabled
+pub fn init() -> bool {
+    let features = unsafe { core::arch::x86_64::__cpuid(1) };
+    if features.edx & CPUID_APIC == 0 {
+        return false;
+    }
+    let madt = match acpi::find_madt() {
+        Some(madt) => madt,
+        None => return false,
+    };
+    if madt.io_apics.is_empty() {
+        return false;
+    }
+
+    let mut io_apics = Vec::new();
+    for info in madt.io_apics.iter() {
+        let base = match ioremap(PhysAddr::new(u64::from(info.address)), IOAPIC_SIZE) {
+            Some(base) => base.as_u64(),
+            None => return false,
+        };
+        let mut io_apic = IoApic {
+            base: base,
+            gsi_base: info.gsi_base,
+            entry_num: 0,
+        };
+        io_apic.entry_num = ((unsafe { io_apic.read(IOAPIC_VER) } >> 16) & 0xff) + 1;
+        for gsi in info.gsi_base..info.gsi_base + io_apic.entry_num {
+            io_apic.set_redirection(gsi, REDIRECTION_MASKED);
+        }
+        io_apics.push(io_apic);
+    }
+
+    let mut apic_base_msr = Msr::new(IA32_APIC_BASE);
+    let apic_base = unsafe { apic_base_msr.read() };
+    // the MSR wins over the MADT, firmware may have moved the registers
+    let lapic_phys = match apic_base & APIC_BASE_ADDR_MASK {
+        0 => madt.local_apic_address,
+        addr => addr,
+    };
+    let lapic_base = match ioremap(PhysAddr::new(lapic_phys), LAPIC_SIZE) {
+        Some(base) => base.as_u64(),
+        None => return false,
+    };
+
+    mask_pics();
+    unsafe {
+        apic_base_msr.write(apic_base | APIC_BASE_ENABLE);
+        LAPIC_BASE = lapic_base;
+        // nothing uses the local interrupt pins or the APIC timer yet
+        lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+        lapic_write(LAPIC_LVT_LINT0, LVT_MASKED);
+        lapic_write(LAPIC_LVT_LINT1, LVT_MASKED);
+        lapic_write(LAPIC_LVT_ERROR, LVT_MASKED);
+        lapic_write(LAPIC_TPR, 0);
+        lapic_write(LAPIC_SVR, SVR_ENABLE | u32::from(SPURIOUS_VECTOR));
+    }
+
+    *CONTROLLERS.lock() = Some(InterruptControllers {
+        io_apics: io_apics,
+        madt: madt,
+        lapic_id: local_apic_id().unwrap_or(0),
+    });
+    true
+}
+
+// deliver the legacy ISA `irq` to `vector` on this processor
+// returns false if no IO-APIC handles it
+pub fn route_legacy_irq(irq: u8, vector: u8) -> bool {
+    let controllers = CONTROLLERS.lock();
+    let controllers = match controllers.as_ref() {
+        Some(controllers) => controllers,
+        None => return false,
+    };
+    // ISA interrupts are edge triggered and active high unless overridden
+    let (gsi, flags) = controllers.madt.legacy_irq(irq);
+    let io_apic = match controllers.io_apic_of(gsi) {
+        Some(io_apic) => io_apic,
+        None => return false,
+    };
+
+    // fixed delivery, physical destination
+    let mut entry = u64::from(vector) | u64::from(controllers.lapic_id) << 56;
+    if let Some(flags) = flags {
+        if flags.active_low() {
+            entry |= REDIRECTION_ACTIVE_LOW;
+        }
+        if flags.level_triggered() {
+            entry |= REDIRECTION_LEVEL;
+        }
+    }
+    io_apic.set_redirection(gsi, entry);
+    true
+}
+
+// stop delivering the legacy ISA `irq`
+pub fn mask_legacy_irq(irq: u8) {
+    let controllers = CONTROLLERS.lock();
+    if let Some(controllers) = controllers.as_ref() {
+        let (gsi, _) = controllers.madt.legacy_irq(irq);
+        if let Some(io_apic) = controllers.io_apic_of(gsi) {
+            io_apic.set_redirection(gsi, REDIRECTION_MASKED);
+        }
+    }
+}
@@ -1,48 +1,42 @@
-use alloc::vec::Vec;
+use crate::kernel_stack::KernelStack;
 use core::mem;
 
-#[derive(Debug, Clone)]
-// NOTE: only store non-volatile registers
-// `fx` and `fx_loc` are in the redox code, but I don't know what they will affect
+// NOTE: only the address space and the stack pointer are kept here,
+// the non-volatile registers and the flags are pushed onto the stack of the thread that
+// switches away and popped again when it is switched back to (see `switch_stacks`)
+#[derive(Debug, Default)]
 pub struct Context {
-    // fx: usize,
     cr3: usize,
-    rflags: usize,
-    rbx: usize,
-    r12: usize,
-    r13: usize,
-    r14: usize,
-    r15: usize,
-    rbp: usize,
-    // stack pointer
+    // stack pointer, the saved registers are right above it
     rsp: usize,
-    // stack content
-    stack: Vec<u8>,
-    // fx_loc: Vec<u8>,
+    // the kernel stack, None for the kernel process which runs on the boot stack
+    stack: Option<KernelStack>,
 }
 
-impl Default for Context {
-    fn default() -> Self {
+// what `switch_stacks` pops, flags first
+const SAVED_REGISTER_NUM: usize = 6;
+// IF clear, bit 1 is always set
+const INITIAL_RFLAGS: usize = 0x2;
+
+impl Context {
+    pub fn new(cr3: usize, stack: KernelStack) -> Self {
+        let rsp = stack.top().as_u64() as usize;
         Context {
-            stack: vec![0_u8; 4096],
-            ..Default::default()
+            cr3: cr3,
+            rsp: rsp,
+            stack: Some(stack),
         }
     }
-}
 
-impl Context {
-    pub fn new(cr3: usize, rsp: usize, stack: Vec<u8>) -> Self {
+    // the context of the code running right now, it is filled in by the first switch away
+    // use for kernel process
+    pub fn current() -> Self {
+        let cr3: usize;
+        unsafe { asm!("mov $0, cr3" : "=r"(cr3) : : "memory" : "intel", "volatile") };
         Context {
             cr3: cr3,
-            rflags: 0,
-            rbx: 0,
-            r12: 0,
-            r13: 0,
-            r14: 0,
-            r15: 0,
-            rbp: rsp,
-            rsp: rsp,
-            stack: stack,
+            rsp: 0,
+            stack: None,
         }
     }
 
@@ -73,56 +67,66 @@ impl Context {
         self.rsp
     }
 
-    // NOTE: this is basically what `save_register` and `restore_register` did in `threads_low.asm`
-    #[cold]
-    #[inline(never)]
-    #[naked]
-    pub unsafe fn switch_to(&mut self, next: &mut Context) {
+    pub fn get_stack(&self) -> Option<&KernelStack> {
+        self.stack.as_ref()
+    }
+
+    // lay out the stack as if the thread had switched away right before calling `entry(arg)`
+    // the first switch to it runs `entry` with interrupts disabled, `entry` never returns
+    pub unsafe fn set_entry(&mut self, entry: extern "C" fn(usize) -> !, arg: usize) {
+        // the return address slot of `entry`, the stack is aligned as after a call
+        self.push_stack(0);
+        self.push_stack(thread_trampoline as usize);
+        // rbp, rbx, r12, r13, r14, r15
+        let registers: [usize; SAVED_REGISTER_NUM] = [0, 0, arg, entry as usize, 0, 0];
+        for &value in registers.iter() {
+            self.push_stack(value);
+        }
+        self.push_stack(INITIAL_RFLAGS);
+    }
+
+    // save the current thread into `self` and continue with `next`
+    // returns once another context switches back to `self`
+    pub unsafe fn switch_to(&mut self, next: &Context) {
         asm!("mov $0, cr3" : "=r"(self.cr3) : : "memory" : "intel", "volatile");
         if next.cr3 != self.cr3 {
             asm!("mov cr3, $0" : : "r"(next.cr3) : "memory" : "intel", "volatile");
         }
-        asm!("pushfq ; pop $0" : "=r"(self.rflags) : : "memory" : "intel", "volatile");
-        asm!("push $0 ; popfq" : : "r"(next.rflags) : "memory" : "intel", "volatile");
-
-        asm!("mov $0, rbx" : "=r"(self.rbx) : : "memory" : "intel", "volatile");
-        asm!("mov rbx, $0" : : "r"(next.rbx) : "memory" : "intel", "volatile");
-
-        asm!("mov $0, r12" : "=r"(self.r12) : : "memory" : "intel", "volatile");
-        asm!("mov r12, $0" : : "r"(next.r12) : "memory" : "intel", "volatile");
-
-        asm!("mov $0, r13" : "=r"(self.r13) : : "memory" : "intel", "volatile");
-        asm!("mov r13, $0" : : "r"(next.r13) : "memory" : "intel", "volatile");
-
-        asm!("mov $0, r14" : "=r"(self.r14) : : "memory" : "intel", "volatile");
-        asm!("mov r14, $0" : : "r"(next.r14) : "memory" : "intel", "volatile");
-
-        asm!("mov $0, r15" : "=r"(self.r15) : : "memory" : "intel", "volatile");
-        asm!("mov r15, $0" : : "r"(next.r15) : "memory" : "intel", "volatile");
-
-        asm!("mov $0, rsp" : "=r"(self.rsp) : : "memory" : "intel", "volatile");
-        asm!("mov rsp, $0" : : "r"(next.rsp) : "memory" : "intel", "volatile");
-
-        asm!("mov $0, rbp" : "=r"(self.rbp) : : "memory" : "intel", "volatile");
-        asm!("mov rbp, $0" : : "r"(next.rbp) : "memory" : "intel", "volatile");
+        switch_stacks(&mut self.rsp, next.rsp);
     }
+}
 
-    // static function
-    // save the current context into a context object
-    // use for kernel process
-    pub fn save_current_context() -> Self {
-        let mut context: Context = Default::default();
-        unsafe {
-            asm!("mov $0, cr3" : "=r"(context.cr3) : : "memory" : "intel", "volatile");
-            asm!("pushfq ; pop $0 " : "=r"(context.rflags) : : "memory" : "intel", "volatile");
-            asm!("mov $0, rbx" : "=r"(context.rbx) : : "memory" : "intel", "volatile");
-            asm!("mov $0, r12" : "=r"(context.r12) : : "memory" : "intel", "volatile");
-            asm!("mov $0, r13" : "=r"(context.r13) : : "memory" : "intel", "volatile");
-            asm!("mov $0, r14" : "=r"(context.r14) : : "memory" : "intel", "volatile");
-            asm!("mov $0, r15" : "=r"(context.r15) : : "memory" : "intel", "volatile");
-            asm!("mov $0, rsp" : "=r"(context.rsp) : : "memory" : "intel", "volatile");
-            asm!("mov $0, rbp" : "=r"(context.rbp) : : "memory" : "intel", "volatile");
-        }
-        context
-    }
+// NOTE: this is basically what `save_register` and `restore_register` did in `threads_low.asm`
+// the callee saved registers and the flags go onto the old stack, its stack pointer into
+// `old_rsp`, then the same is popped from the new stack and `ret` continues where it left off
+#[cold]
+#[inline(never)]
+#[naked]
+unsafe extern "C" fn switch_stacks(_old_rsp: *mut usize, _new_rsp: usize) {
+    asm!("push rbp
+          push rbx
+          push r12
+          push r13
+          push r14
+          push r15
+          pushfq
+          mov [rdi], rsp
+          mov rsp, rsi
+          popfq
+          pop r15
+          pop r14
+          pop r13
+          pop r12
+          pop rbx
+          pop rbp
+          ret"
+         : : : "memory" : "intel", "volatile");
+}
+
+// the first `ret` of a new thread lands here with the argument in r12 and the entry in r13
+#[naked]
+unsafe extern "C" fn thread_trampoline() {
+    asm!("mov rdi, r12
+          jmp r13"
+         : : : : "intel", "volatile");
 }
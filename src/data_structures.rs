@@ -1,5 +1,7 @@
 use crate::println;
+use core::cell::UnsafeCell;
 use core::fmt::Debug;
+use core::sync::atomic::{self, AtomicUsize};
 
 #[derive(Copy, Clone)]
 pub struct LinkedListNode<T>
@@ -81,7 +83,8 @@ where
 
     // remove the first node (starting from the head)
     // whose content is the same as the given `content`
-    pub fn remove(&mut self, content: T) {
+    // returns false if there is no such node
+    pub fn remove(&mut self, content: T) -> bool {
         // pointer that points to the current node
         let mut pt = &mut self.head;
         while !pt.next.is_null() {
@@ -90,10 +93,11 @@ where
                 pt.next = unsafe { (*pt.next).next };
                 self.size -= 1;
                 // TODO: thr raw pointer have no gc?
-                return;
+                return true;
             }
             pt = unsafe { &mut (*pt.next) };
         }
+        false
     }
 
     // pop out the first element
@@ -191,4 +195,469 @@ impl FrameInfo {
     pub fn set_level(&mut self, level: u32) {
         self.level = level;
     }
+
+    // `count` is the number of page table entries mapping this frame
+    pub fn inc_count(&mut self) -> u16 {
+        self.count += 1;
+        self.count
+    }
+
+    pub fn dec_count(&mut self) -> u16 {
+        if self.count > 0 {
+            self.count -= 1;
+        }
+        self.count
+    }
+}
+
+// NOTE: an AVL tree, used where we need ordered lookups such as "the range containing addr"
+// unlike `LinkedList`, the nodes live on the kernel heap
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+type AvlLink<K, V> = Option<Box<AvlNode<K, V>>>;
+
+struct AvlNode<K, V>
+where
+    K: Ord + Copy,
+{
+    key: K,
+    value: V,
+    height: i32,
+    left: AvlLink<K, V>,
+    right: AvlLink<K, V>,
+}
+
+impl<K, V> AvlNode<K, V>
+where
+    K: Ord + Copy,
+{
+    fn new(key: K, value: V) -> Self {
+        AvlNode {
+            key: key,
+            value: value,
+            height: 1,
+            left: None,
+            right: None,
+        }
+    }
+
+    fn update_height(&mut self) {
+        self.height = 1 + core::cmp::max(height(&self.left), height(&self.right));
+    }
+
+    fn balance_factor(&self) -> i32 {
+        height(&self.left) - height(&self.right)
+    }
+}
+
+fn height<K: Ord + Copy, V>(link: &AvlLink<K, V>) -> i32 {
+    match link {
+        Some(node) => node.height,
+        None => 0,
+    }
+}
+
+fn rotate_right<K: Ord + Copy, V>(mut node: Box<AvlNode<K, V>>) -> Box<AvlNode<K, V>> {
+    let mut left = node.left.take().expect("rotate_right without left child");
+    node.left = left.right.take();
+    node.update_height();
+    left.right = Some(node);
+    left.update_height();
+    left
+}
+
+fn rotate_left<K: Ord + Copy, V>(mut node: Box<AvlNode<K, V>>) -> Box<AvlNode<K, V>> {
+    let mut right = node.right.take().expect("rotate_left without right child");
+    node.right = right.left.take();
+    node.update_height();
+    right.left = Some(node);
+    right.update_height();
+    right
+}
+
+fn rebalance<K: Ord + Copy, V>(mut node: Box<AvlNode<K, V>>) -> Box<AvlNode<K, V>> {
+    node.update_height();
+    let balance = node.balance_factor();
+    if balance > 1 {
+        // left-right case
+        if node.left.as_ref().map_or(0, |n| n.balance_factor()) < 0 {
+            node.left = node.left.take().map(rotate_left);
+        }
+        return rotate_right(node);
+    }
+    if balance < -1 {
+        // right-left case
+        if node.right.as_ref().map_or(0, |n| n.balance_factor()) > 0 {
+            node.right = node.right.take().map(rotate_right);
+        }
+        return rotate_left(node);
+    }
+    node
+}
+
+fn avl_insert<K: Ord + Copy, V>(
+    link: AvlLink<K, V>,
+    key: K,
+    value: V,
+    old: &mut Option<V>,
+) -> Box<AvlNode<K, V>> {
+    let mut node = match link {
+        Some(node) => node,
+        None => return Box::new(AvlNode::new(key, value)),
+    };
+    match key.cmp(&node.key) {
+        Ordering::Less => node.left = Some(avl_insert(node.left.take(), key, value, old)),
+        Ordering::Greater => node.right = Some(avl_insert(node.right.take(), key, value, old)),
+        Ordering::Equal => {
+            *old = Some(core::mem::replace(&mut node.value, value));
+            return node;
+        }
+    }
+    rebalance(node)
+}
+
+// detach the smallest node of a subtree
+// returns (the rest of the subtree, the smallest node)
+fn avl_remove_min<K: Ord + Copy, V>(
+    mut node: Box<AvlNode<K, V>>,
+) -> (AvlLink<K, V>, Box<AvlNode<K, V>>) {
+    match node.left.take() {
+        None => {
+            let right = node.right.take();
+            (right, node)
+        }
+        Some(left) => {
+            let (new_left, min) = avl_remove_min(left);
+            node.left = new_left;
+            (Some(rebalance(node)), min)
+        }
+    }
+}
+
+fn avl_remove<K: Ord + Copy, V>(
+    link: AvlLink<K, V>,
+    key: &K,
+    removed: &mut Option<V>,
+) -> AvlLink<K, V> {
+    let mut node = link?;
+    match key.cmp(&node.key) {
+        Ordering::Less => node.left = avl_remove(node.left.take(), key, removed),
+        Ordering::Greater => node.right = avl_remove(node.right.take(), key, removed),
+        Ordering::Equal => {
+            let left = node.left.take();
+            let right = node.right.take();
+            *removed = Some(node.value);
+            return match (left, right) {
+                (None, right) => right,
+                (left, None) => left,
+                (left, Some(right)) => {
+                    let (new_right, mut min) = avl_remove_min(right);
+                    min.left = left;
+                    min.right = new_right;
+                    Some(rebalance(min))
+                }
+            };
+        }
+    }
+    Some(rebalance(node))
+}
+
+pub struct AvlTree<K, V>
+where
+    K: Ord + Copy,
+{
+    root: AvlLink<K, V>,
+    size: usize,
+}
+
+impl<K, V> AvlTree<K, V>
+where
+    K: Ord + Copy,
+{
+    pub fn new() -> Self {
+        AvlTree {
+            root: None,
+            size: 0,
+        }
+    }
+
+    // returns the old value if `key` was already in the tree
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let mut old = None;
+        self.root = Some(avl_insert(self.root.take(), key, value, &mut old));
+        if old.is_none() {
+            self.size += 1;
+        }
+        old
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let mut removed = None;
+        self.root = avl_remove(self.root.take(), key, &mut removed);
+        if removed.is_some() {
+            self.size -= 1;
+        }
+        removed
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let mut link = &self.root;
+        while let Some(node) = link {
+            match key.cmp(&node.key) {
+                Ordering::Less => link = &node.left,
+                Ordering::Greater => link = &node.right,
+                Ordering::Equal => return Some(&node.value),
+            }
+        }
+        None
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let mut link = &mut self.root;
+        while let Some(node) = link {
+            match key.cmp(&node.key) {
+                Ordering::Less => link = &mut node.left,
+                Ordering::Greater => link = &mut node.right,
+                Ordering::Equal => return Some(&mut node.value),
+            }
+        }
+        None
+    }
+
+    // the entry with the greatest key <= `key`
+    pub fn floor(&self, key: &K) -> Option<(K, &V)> {
+        let mut link = &self.root;
+        let mut best = None;
+        while let Some(node) = link {
+            match key.cmp(&node.key) {
+                Ordering::Less => link = &node.left,
+                Ordering::Greater => {
+                    best = Some((node.key, &node.value));
+                    link = &node.right;
+                }
+                Ordering::Equal => return Some((node.key, &node.value)),
+            }
+        }
+        best
+    }
+
+    // the entry with the smallest key >= `key`
+    pub fn ceiling(&self, key: &K) -> Option<(K, &V)> {
+        let mut link = &self.root;
+        let mut best = None;
+        while let Some(node) = link {
+            match key.cmp(&node.key) {
+                Ordering::Less => {
+                    best = Some((node.key, &node.value));
+                    link = &node.left;
+                }
+                Ordering::Greater => link = &node.right,
+                Ordering::Equal => return Some((node.key, &node.value)),
+            }
+        }
+        best
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    // in-order (ascending key) iterator
+    pub fn iter(&self) -> AvlIter<K, V> {
+        let mut iter = AvlIter { stack: Vec::new() };
+        iter.push_left(&self.root);
+        iter
+    }
+}
+
+impl<K, V> Default for AvlTree<K, V>
+where
+    K: Ord + Copy,
+{
+    fn default() -> Self {
+        AvlTree::new()
+    }
+}
+
+pub struct AvlIter<'a, K, V>
+where
+    K: Ord + Copy,
+{
+    stack: Vec<&'a AvlNode<K, V>>,
+}
+
+impl<'a, K, V> AvlIter<'a, K, V>
+where
+    K: Ord + Copy,
+{
+    fn push_left(&mut self, mut link: &'a AvlLink<K, V>) {
+        while let Some(node) = link {
+            self.stack.push(node);
+            link = &node.left;
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for AvlIter<'a, K, V>
+where
+    K: Ord + Copy,
+{
+    type Item = (K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        self.push_left(&node.right);
+        Some((node.key, &node.value))
+    }
+}
+
+// NOTE: lock-free ring buffer for one producer and one consumer
+//
+// The producer only writes `tail`, the consumer only writes `head`, so an interrupt handler
+// can push while a task pops without a lock. One slot always stays empty to tell a full
+// buffer from an empty one, so it holds `RING_BUFFER_CAPACITY - 1` items.
+pub const RING_BUFFER_CAPACITY: usize = 128;
+
+pub struct RingBuffer<T>
+where
+    T: Copy + Default,
+{
+    buf: UnsafeCell<[T; RING_BUFFER_CAPACITY]>,
+    // the next slot to read
+    head: AtomicUsize,
+    // the next slot to write
+    tail: AtomicUsize,
+}
+
+unsafe impl<T> Sync for RingBuffer<T> where T: Copy + Default + Send {}
+
+impl<T> RingBuffer<T>
+where
+    T: Copy + Default,
+{
+    pub fn new() -> Self {
+        RingBuffer {
+            buf: UnsafeCell::new([T::default(); RING_BUFFER_CAPACITY]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    // only called by the producer, returns false if the buffer is full
+    pub fn push(&self, item: T) -> bool {
+        let tail = self.tail.load(atomic::Ordering::Relaxed);
+        let next = (tail + 1) % RING_BUFFER_CAPACITY;
+        if next == self.head.load(atomic::Ordering::Acquire) {
+            return false;
+        }
+        unsafe { (*self.buf.get())[tail] = item };
+        self.tail.store(next, atomic::Ordering::Release);
+        true
+    }
+
+    // only called by the consumer
+    pub fn pop(&self) -> Option<T> {
+        let head = self.head.load(atomic::Ordering::Relaxed);
+        if head == self.tail.load(atomic::Ordering::Acquire) {
+            return None;
+        }
+        let item = unsafe { (*self.buf.get())[head] };
+        self.head.store((head + 1) % RING_BUFFER_CAPACITY, atomic::Ordering::Release);
+        Some(item)
+    }
+
+    pub fn len(&self) -> usize {
+        let head = self.head.load(atomic::Ordering::Acquire);
+        let tail = self.tail.load(atomic::Ordering::Acquire);
+        (tail + RING_BUFFER_CAPACITY - head) % RING_BUFFER_CAPACITY
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Default for RingBuffer<T>
+where
+    T: Copy + Default,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check_balanced<K: Ord + Copy, V>(link: &AvlLink<K, V>) -> i32 {
+        match link {
+            None => 0,
+            Some(node) => {
+                let left = check_balanced(&node.left);
+                let right = check_balanced(&node.right);
+                assert!((left - right).abs() <= 1);
+                assert_eq!(node.height, 1 + core::cmp::max(left, right));
+                node.height
+            }
+        }
+    }
+
+    #[test]
+    fn avl_insert_remove() {
+        let mut tree = AvlTree::new();
+        for i in 0..1000 {
+            assert!(tree.insert((i * 7919) % 1000, i).is_none());
+        }
+        assert_eq!(tree.size(), 1000);
+        check_balanced(&tree.root);
+
+        for i in (0..1000).step_by(2) {
+            assert!(tree.remove(&i).is_some());
+        }
+        assert_eq!(tree.size(), 500);
+        assert!(tree.get(&2).is_none());
+        assert!(tree.get(&3).is_some());
+        check_balanced(&tree.root);
+
+        let keys: Vec<usize> = tree.iter().map(|(k, _)| k).collect();
+        let expected: Vec<usize> = (0..1000).filter(|k| k % 2 == 1).collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn avl_floor_ceiling() {
+        let mut tree = AvlTree::new();
+        for &key in [10, 20, 30].iter() {
+            tree.insert(key, ());
+        }
+        assert_eq!(tree.floor(&5).map(|(k, _)| k), None);
+        assert_eq!(tree.floor(&25).map(|(k, _)| k), Some(20));
+        assert_eq!(tree.floor(&30).map(|(k, _)| k), Some(30));
+        assert_eq!(tree.ceiling(&25).map(|(k, _)| k), Some(30));
+        assert_eq!(tree.ceiling(&31).map(|(k, _)| k), None);
+    }
+
+    #[test]
+    fn ring_buffer_wraps_around() {
+        let ring = RingBuffer::new();
+        assert!(ring.pop().is_none());
+        for round in 0..3 {
+            for i in 0..RING_BUFFER_CAPACITY - 1 {
+                assert!(ring.push(round * 1000 + i));
+            }
+            assert!(!ring.push(0));
+            assert_eq!(ring.len(), RING_BUFFER_CAPACITY - 1);
+            for i in 0..RING_BUFFER_CAPACITY - 1 {
+                assert_eq!(ring.pop(), Some(round * 1000 + i));
+            }
+            assert!(ring.is_empty());
+        }
+    }
 }
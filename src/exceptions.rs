@@ -0,0 +1,199 @@
+use crate::hlt_loop;
+use crate::interrupt_stats;
+use crate::println;
+
+use core::fmt;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+// NOTE: handlers for the architecturally defined exceptions
+// breakpoint, double fault and page fault have their own handlers in `interrupts`
+// there is no user mode yet (no ring 3 segments, no kernel stack in the TSS to switch to),
+// every fault happens in the kernel and stops everything
+pub const DIVIDE_ERROR: u8 = 0;
+pub const DEBUG: u8 = 1;
+pub const NON_MASKABLE_INTERRUPT: u8 = 2;
+pub const BREAKPOINT: u8 = 3;
+pub const OVERFLOW: u8 = 4;
+pub const BOUND_RANGE_EXCEEDED: u8 = 5;
+pub const INVALID_OPCODE: u8 = 6;
+pub const DEVICE_NOT_AVAILABLE: u8 = 7;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const INVALID_TSS: u8 = 10;
+pub const SEGMENT_NOT_PRESENT: u8 = 11;
+pub const STACK_SEGMENT_FAULT: u8 = 12;
+pub const GENERAL_PROTECTION_FAULT: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+pub const X87_FLOATING_POINT: u8 = 16;
+pub const ALIGNMENT_CHECK: u8 = 17;
+pub const MACHINE_CHECK: u8 = 18;
+pub const SIMD_FLOATING_POINT: u8 = 19;
+pub const VIRTUALIZATION: u8 = 20;
+pub const SECURITY_EXCEPTION: u8 = 30;
+
+const EXCEPTION_NAMES: [(&str, &str); 32] = [
+    ("DIVIDE ERROR", "#DE"),
+    ("DEBUG", "#DB"),
+    ("NON-MASKABLE INTERRUPT", "NMI"),
+    ("BREAKPOINT", "#BP"),
+    ("OVERFLOW", "#OF"),
+    ("BOUND RANGE EXCEEDED", "#BR"),
+    ("INVALID OPCODE", "#UD"),
+    ("DEVICE NOT AVAILABLE", "#NM"),
+    ("DOUBLE FAULT", "#DF"),
+    ("COPROCESSOR SEGMENT OVERRUN", "-"),
+    ("INVALID TSS", "#TS"),
+    ("SEGMENT NOT PRESENT", "#NP"),
+    ("STACK-SEGMENT FAULT", "#SS"),
+    ("GENERAL PROTECTION FAULT", "#GP"),
+    ("PAGE FAULT", "#PF"),
+    ("RESERVED", "-"),
+    ("X87 FLOATING-POINT EXCEPTION", "#MF"),
+    ("ALIGNMENT CHECK", "#AC"),
+    ("MACHINE CHECK", "#MC"),
+    ("SIMD FLOATING-POINT EXCEPTION", "#XM"),
+    ("VIRTUALIZATION EXCEPTION", "#VE"),
+    ("CONTROL PROTECTION EXCEPTION", "#CP"),
+    ("RESERVED", "-"),
+    ("RESERVED", "-"),
+    ("RESERVED", "-"),
+    ("RESERVED", "-"),
+    ("RESERVED", "-"),
+    ("RESERVED", "-"),
+    ("HYPERVISOR INJECTION EXCEPTION", "#HV"),
+    ("VMM COMMUNICATION EXCEPTION", "#VC"),
+    ("SECURITY EXCEPTION", "#SX"),
+    ("RESERVED", "-"),
+];
+
+pub fn exception_name(vector: u8) -> &'static str {
+    EXCEPTION_NAMES
+        .get(vector as usize)
+        .map_or("UNKNOWN", |&(name, _)| name)
+}
+
+// the error code of #TS, #NP, #SS and #GP refers to a segment selector
+// (or is 0 if the exception is not related to one)
+pub struct SelectorErrorCode(pub u64);
+
+impl SelectorErrorCode {
+    // the exception happened while delivering an external event
+    pub fn external(&self) -> bool {
+        self.0 & 0x1 != 0
+    }
+
+    pub fn table(&self) -> &'static str {
+        match (self.0 >> 1) & 0x3 {
+            0 => "GDT",
+            2 => "LDT",
+            _ => "IDT",
+        }
+    }
+
+    pub fn index(&self) -> u64 {
+        (self.0 >> 3) & 0x1fff
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "{:#x} (not selector related)", self.0);
+        }
+        write!(f, "{:#x} ({} index {}", self.0, self.table(), self.index())?;
+        if self.external() {
+            write!(f, ", external")?;
+        }
+        write!(f, ")")
+    }
+}
+
+struct ErrorCodeDisplay {
+    vector: u8,
+    error_code: u64,
+}
+
+impl fmt::Display for ErrorCodeDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.vector {
+            INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT => {
+                write!(f, "{}", SelectorErrorCode(self.error_code))
+            }
+            _ => write!(f, "{:#x}", self.error_code),
+        }
+    }
+}
+
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    idt.divide_by_zero.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.non_maskable_interrupt.set_handler_fn(non_maskable_interrupt_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available.set_handler_fn(device_not_available_handler);
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+    idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check.set_handler_fn(machine_check_handler);
+    idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.security_exception.set_handler_fn(security_exception_handler);
+}
+
+macro_rules! exception_handler {
+    ($name:ident, $vector:expr) => {
+        extern "x86-interrupt" fn $name(stack_frame: &mut InterruptStackFrame) {
+            handle_exception($vector, stack_frame, None);
+        }
+    };
+}
+
+macro_rules! exception_handler_with_error_code {
+    ($name:ident, $vector:expr) => {
+        extern "x86-interrupt" fn $name(stack_frame: &mut InterruptStackFrame, error_code: u64) {
+            handle_exception($vector, stack_frame, Some(error_code));
+        }
+    };
+}
+
+exception_handler!(divide_error_handler, DIVIDE_ERROR);
+exception_handler!(debug_handler, DEBUG);
+exception_handler!(non_maskable_interrupt_handler, NON_MASKABLE_INTERRUPT);
+exception_handler!(overflow_handler, OVERFLOW);
+exception_handler!(bound_range_exceeded_handler, BOUND_RANGE_EXCEEDED);
+exception_handler!(invalid_opcode_handler, INVALID_OPCODE);
+exception_handler!(device_not_available_handler, DEVICE_NOT_AVAILABLE);
+exception_handler_with_error_code!(invalid_tss_handler, INVALID_TSS);
+exception_handler_with_error_code!(segment_not_present_handler, SEGMENT_NOT_PRESENT);
+exception_handler_with_error_code!(stack_segment_fault_handler, STACK_SEGMENT_FAULT);
+exception_handler_with_error_code!(general_protection_fault_handler, GENERAL_PROTECTION_FAULT);
+exception_handler!(x87_floating_point_handler, X87_FLOATING_POINT);
+exception_handler_with_error_code!(alignment_check_handler, ALIGNMENT_CHECK);
+exception_handler!(machine_check_handler, MACHINE_CHECK);
+exception_handler!(simd_floating_point_handler, SIMD_FLOATING_POINT);
+exception_handler!(virtualization_handler, VIRTUALIZATION);
+exception_handler_with_error_code!(security_exception_handler, SECURITY_EXCEPTION);
+
+fn handle_exception(vector: u8, stack_frame: &mut InterruptStackFrame, error_code: Option<u64>) {
+    let _handler_timer = interrupt_stats::enter(vector);
+    let (name, mnemonic) = EXCEPTION_NAMES[vector as usize];
+    println!("EXCEPTION: {} ({}, vector {})", name, mnemonic, vector);
+    if let Some(error_code) = error_code {
+        let error_code = ErrorCodeDisplay {
+            vector: vector,
+            error_code: error_code,
+        };
+        println!("Error Code: {}", error_code);
+    }
+    println!("{:#?}", stack_frame);
+
+    match vector {
+        // traps, the instruction is already done and execution simply continues
+        DEBUG | OVERFLOW | NON_MASKABLE_INTERRUPT => (),
+        // a fault in the kernel, or a machine check leaving the machine in an undefined state
+        _ => hlt_loop(),
+    }
+}
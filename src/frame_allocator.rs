@@ -4,8 +4,9 @@ use crate::memory::phys2virt;
 use core::mem::size_of;
 use core::ptr;
 use libm::{ceil, floor, log2, log2f};
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::structures::paging::page::{PageSize, Size4KiB};
-use x86_64::structures::paging::{frame::PhysFrame, FrameAllocator};
+use x86_64::structures::paging::{frame::PhysFrame, FrameAllocator, FrameDeallocator};
 use x86_64::PhysAddr;
 
 const LEVEL_NUM: usize = 11;
@@ -217,44 +218,94 @@ impl Region {
     pub fn retrieve_frame(&mut self, frame_info: &mut FrameInfo) {
         let frame_idx = frame_info.get_index();
         // should not happen
-        if frame_idx < self.start_frame_idx || frame_idx > (self.start_frame_idx + self.size) {
+        if frame_idx < self.start_frame_idx || frame_idx >= (self.start_frame_idx + self.size) {
+            return;
+        }
+        // only the HEAD of an allocated buddy can be freed, this catches double frees
+        if (frame_info.get_flgs().bits() & FrameFlags::HEAD.bits()) == 0 {
             return;
         }
 
-        let rel_frame_idx = frame_idx - self.start_frame_idx;
+        let mut rel_frame_idx = frame_idx - self.start_frame_idx;
         let mut level = frame_info.get_level() as usize;
-        self.free_frame_num -= 1 << level;
+        // the frame is no longer the HEAD of an allocated buddy
+        frame_info.reset_flgs();
+        self.free_frame_num += 1 << level;
         // NOTE: this is the merge process
-        // NOTE: this merge has a problem. Should I only consider merging only the frames that
-        // `used` to be a whole chunk?
-        // FIXME: may be buggy
+        // the buddy of a block at `idx` is at `idx ^ (1 << level)`,
+        // blocks are aligned to their size inside the region
         while level < (LEVEL_NUM - 1) {
-            let half_frame_idx = rel_frame_idx + 2usize.pow(level as u32);
-            if half_frame_idx > self.size {
+            let buddy_idx = rel_frame_idx ^ (1 << level);
+            if buddy_idx + (1 << level) > self.size {
                 break;
             }
-            let half_frame = unsafe { &mut *self.frame_map.offset(half_frame_idx as isize) };
-            if !is_free_buddy_frame(half_frame, level as u32) {
+            let buddy_frame = unsafe { &mut *self.frame_map.offset(buddy_idx as isize) };
+            if !is_free_buddy_frame(buddy_frame, level as u32) {
                 break;
             }
-            // reset the level of this frame chunk
+            // frames in the middle of an allocated block look free as well,
+            // only a buddy that is actually in the free list can be merged
+            if !self.free_lists[level].remove(buddy_idx) {
+                break;
+            }
+            // reset the level of the upper half
             // meaning it is merged
-            half_frame.set_level(0);
-            self.free_lists[level].remove(half_frame_idx);
+            let upper_idx = core::cmp::max(rel_frame_idx, buddy_idx);
+            unsafe { (*self.frame_map.offset(upper_idx as isize)).set_level(0) };
+            rel_frame_idx = core::cmp::min(rel_frame_idx, buddy_idx);
             // the level is upgraded
             level += 1;
         }
-        // put the merged frame back into the corresponding
-        let merged_frame = unsafe { &mut *self.frame_map.offset(frame_idx as isize) };
+        // put the merged frame back into the corresponding free list
+        let merged_frame = unsafe { &mut *self.frame_map.offset(rel_frame_idx as isize) };
         merged_frame.set_level(level as u32);
         let node = merged_frame.get_direct_access() as *mut LinkedListNode<usize>;
-        unsafe { (*node).init(frame_idx) };
+        unsafe { (*node).init(rel_frame_idx) };
         self.free_lists[level].append(node);
     }
+
+    pub fn free_frame_num(&self) -> usize {
+        self.free_frame_num
+    }
+
+    // look up the FrameInfo of a global frame index
+    // returns None if the frame does not belong to this region
+    pub fn get_frame_info(&mut self, frame_idx: usize) -> Option<&'static mut FrameInfo> {
+        if frame_idx < self.start_frame_idx || frame_idx >= (self.start_frame_idx + self.size) {
+            return None;
+        }
+        let rel_frame_idx = frame_idx - self.start_frame_idx;
+        Some(unsafe { &mut *self.frame_map.offset(rel_frame_idx as isize) })
+    }
 }
 
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 
+// NOTE: the frame allocator is owned by the kernel heap allocator,
+// this pointer lets interrupt handlers (e.g. page fault) reach it
+pub static mut FRAME_ALLOCATOR: *mut SimpleFrameAllocator = ptr::null_mut();
+
+pub fn set_frame_allocator(frame_allocator: *mut SimpleFrameAllocator) {
+    unsafe { FRAME_ALLOCATOR = frame_allocator };
+}
+
+// NOTE: called when a single frame cannot be allocated
+// it tries to free at least the given number of frames and returns how many it freed
+// set by `swap::init`, the allocator itself knows nothing about page tables
+static mut RECLAIM_HOOK: Option<fn(usize) -> usize> = None;
+const RECLAIM_BATCH: usize = 16;
+
+pub fn set_reclaim_hook(hook: fn(usize) -> usize) {
+    unsafe { RECLAIM_HOOK = Some(hook) };
+}
+
+pub fn get_frame_allocator() -> &'static mut SimpleFrameAllocator {
+    unsafe {
+        assert!(!FRAME_ALLOCATOR.is_null(), "frame allocator is not initialized");
+        &mut *FRAME_ALLOCATOR
+    }
+}
+
 #[derive(Default)]
 #[repr(C)]
 pub struct SimpleFrameAllocator {
@@ -286,7 +337,25 @@ impl SimpleFrameAllocator {
         self.region_num += 1;
     }
 
+    // the regions are shared with interrupt handlers and other processes,
+    // they are only touched with interrupts disabled
     pub fn alloc_frames(&mut self, frame_num: usize) -> Option<&'static mut FrameInfo> {
+        without_interrupts(|| {
+            if let Some(frame_info) = self.request_from_regions(frame_num) {
+                return Some(frame_info);
+            }
+            // reclaimed frames are scattered, so only single frames are retried
+            let reclaim = unsafe { RECLAIM_HOOK };
+            if let (1, Some(reclaim)) = (frame_num, reclaim) {
+                if reclaim(RECLAIM_BATCH) > 0 {
+                    return self.request_from_regions(frame_num);
+                }
+            }
+            None
+        })
+    }
+
+    fn request_from_regions(&mut self, frame_num: usize) -> Option<&'static mut FrameInfo> {
         // I do reverse order because the second region is larger
         for region_idx in (0..MAX_REGION_NUM).rev() {
             if let Some(frame_info) = self.regions[region_idx].request_frames(frame_num) {
@@ -297,14 +366,46 @@ impl SimpleFrameAllocator {
     }
 
     pub fn dealloc_frame(&mut self, frame_info: &mut FrameInfo) {
-        for region_idx in (0..MAX_REGION_NUM).rev() {
-            self.regions[region_idx].retrieve_frame(frame_info);
-        }
+        without_interrupts(|| {
+            for region_idx in (0..MAX_REGION_NUM).rev() {
+                self.regions[region_idx].retrieve_frame(frame_info);
+            }
+        });
     }
 
     pub fn region_num(&self) -> usize {
         self.region_num
     }
+
+    pub fn free_frame_num(&self) -> usize {
+        self.regions[..self.region_num]
+            .iter()
+            .map(|region| region.free_frame_num())
+            .sum()
+    }
+
+    pub fn frame_info(&mut self, frame: PhysFrame<Size4KiB>) -> Option<&'static mut FrameInfo> {
+        let frame_idx = (frame.start_address().as_u64() / Size4KiB::SIZE) as usize;
+        for region_idx in 0..self.region_num {
+            if let Some(frame_info) = self.regions[region_idx].get_frame_info(frame_idx) {
+                return Some(frame_info);
+            }
+        }
+        None
+    }
+
+    // allocate a single frame and fill it with zeros
+    pub fn alloc_zeroed_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let frame_info = self.alloc_frames(1)?;
+        let frame_size = Size4KiB::SIZE as usize;
+        unsafe { ptr::write_bytes(frame_info.get_direct_access() as *mut u8, 0, frame_size) };
+        Some(frame_of(frame_info))
+    }
+}
+
+pub fn frame_of(frame_info: &FrameInfo) -> PhysFrame<Size4KiB> {
+    let frame_size = Size4KiB::SIZE as usize;
+    PhysFrame::containing_address(PhysAddr::new((frame_info.get_index() * frame_size) as u64))
 }
 
 // align a size number to a multiple of the unit
@@ -345,11 +446,16 @@ fn is_free_buddy_frame(frame_info: &mut FrameInfo, level: u32) -> bool {
 unsafe impl FrameAllocator<Size4KiB> for SimpleFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
         if let Some(frame_info) = self.alloc_frames(1) {
-            let frame_size = Size4KiB::SIZE as usize;
-            return Some(PhysFrame::containing_address(PhysAddr::new(
-                (frame_info.get_index() * frame_size) as u64,
-            )));
+            return Some(frame_of(frame_info));
         }
         None
     }
+}
+
+impl FrameDeallocator<Size4KiB> for SimpleFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        if let Some(frame_info) = self.frame_info(frame) {
+            self.dealloc_frame(frame_info);
+        }
+    }
 }
\ No newline at end of file
@@ -4,21 +4,63 @@ use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+// NOTE: the first page of the double fault stack is a guard page,
+// it is unmapped by `protect_double_fault_stack` once paging is set up
+const GUARD_SIZE: usize = 4096;
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+
+#[repr(align(4096))]
+struct DoubleFaultStack([u8; GUARD_SIZE + DOUBLE_FAULT_STACK_SIZE]);
+
+static mut DOUBLE_FAULT_STACK: DoubleFaultStack =
+    DoubleFaultStack([0; GUARD_SIZE + DOUBLE_FAULT_STACK_SIZE]);
+
+fn double_fault_guard_page() -> VirtAddr {
+    VirtAddr::from_ptr(unsafe { &DOUBLE_FAULT_STACK })
+}
+
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
+            let stack_start = double_fault_guard_page() + GUARD_SIZE;
+            let stack_end = stack_start + DOUBLE_FAULT_STACK_SIZE;
             stack_end
         };
         tss
     };
 }
 
+// unmap the guard page below the double fault stack
+// must be called after `memory::init`
+pub fn protect_double_fault_stack() {
+    use crate::memory;
+    use crate::page_fault::register_kernel_vma;
+    use crate::vma::{Vma, VmaBacking, VmaFlags};
+    use crate::PHYSICAL_MEMORY_OFFSET;
+    use x86_64::structures::paging::{Mapper, Page, Size4KiB};
+
+    let guard = double_fault_guard_page();
+    let page: Page<Size4KiB> = Page::containing_address(guard);
+    let mut mapper = unsafe { memory::init(PHYSICAL_MEMORY_OFFSET as u64) };
+    // the frame belongs to the kernel image, so it is not returned to the frame allocator
+    if let Ok((_, flush)) = mapper.unmap(page) {
+        flush.flush();
+    }
+    let guard_vma = Vma::new(
+        guard.as_u64(),
+        guard.as_u64() + GUARD_SIZE as u64,
+        VmaFlags::empty(),
+        VmaBacking::StackGuard,
+    );
+    let _ = register_kernel_vma(guard_vma);
+}
+
+pub fn is_double_fault_guard(addr: VirtAddr) -> bool {
+    let guard = double_fault_guard_page();
+    addr >= guard && addr < guard + GUARD_SIZE
+}
+
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 
 struct Selector {
@@ -0,0 +1,144 @@
+use crate::apic::SPURIOUS_VECTOR;
+use crate::exceptions::exception_name;
+use crate::interrupts::PIC_1_OFFSET;
+use crate::irq::IRQ_NUM;
+use crate::println;
+use crate::tsc;
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+// NOTE: per-vector interrupt statistics
+//
+// Every handler counts its vector on entry and adds the TSC cycles it took when it returns.
+// Handlers that never return (e.g. a fatal fault) are counted but not timed. Deferred work
+// runs after the handler is done and is not part of its time.
+// Interrupts the controller raised without a pending request (the 8259 sends them as
+// IRQ 7 or IRQ 15, the local APIC on its spurious vector) only count as spurious.
+pub const VECTOR_NUM: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VectorStats {
+    pub count: u64,
+    pub spurious: u64,
+    // TSC cycles spent in the handler
+    pub cycles: u64,
+    pub max_cycles: u64,
+}
+
+impl VectorStats {
+    // 0 while the TSC is not calibrated
+    pub fn total_nanos(&self) -> u64 {
+        tsc::cycles_to_nanos(self.cycles)
+    }
+
+    pub fn average_nanos(&self) -> u64 {
+        match self.count - self.spurious {
+            0 => 0,
+            handled => tsc::cycles_to_nanos(self.cycles / handled),
+        }
+    }
+}
+
+// only updated by the handlers, which run with interrupts disabled
+static mut VECTOR_STATS: [VectorStats; VECTOR_NUM] = [VectorStats {
+    count: 0,
+    spurious: 0,
+    cycles: 0,
+    max_cycles: 0,
+}; VECTOR_NUM];
+
+// counts the vector when created and adds the time in between when dropped
+pub struct HandlerTimer {
+    vector: u8,
+    start: u64,
+}
+
+impl Drop for HandlerTimer {
+    fn drop(&mut self) {
+        let cycles = tsc::cycles().wrapping_sub(self.start);
+        let stats = unsafe { &mut VECTOR_STATS[self.vector as usize] };
+        stats.cycles += cycles;
+        stats.max_cycles = stats.max_cycles.max(cycles);
+    }
+}
+
+// call first thing in a handler and keep the result until the handler is done
+pub fn enter(vector: u8) -> HandlerTimer {
+    unsafe { VECTOR_STATS[vector as usize].count += 1 };
+    HandlerTimer {
+        vector: vector,
+        start: tsc::cycles(),
+    }
+}
+
+pub fn count_spurious(vector: u8) {
+    unsafe {
+        VECTOR_STATS[vector as usize].count += 1;
+        VECTOR_STATS[vector as usize].spurious += 1;
+    }
+}
+
+pub fn vector_stats(vector: u8) -> VectorStats {
+    without_interrupts(|| unsafe { VECTOR_STATS[vector as usize] })
+}
+
+pub fn total_interrupts() -> u64 {
+    (0..VECTOR_NUM).map(|vector| vector_stats(vector as u8).count).sum()
+}
+
+pub fn reset() {
+    without_interrupts(|| unsafe {
+        for stats in VECTOR_STATS.iter_mut() {
+            *stats = VectorStats::default();
+        }
+    })
+}
+
+fn vector_name(vector: u8) -> &'static str {
+    const IRQ_NAMES: [&str; IRQ_NUM] = [
+        "IRQ 0 (TIMER)",
+        "IRQ 1 (KEYBOARD)",
+        "IRQ 2",
+        "IRQ 3",
+        "IRQ 4",
+        "IRQ 5",
+        "IRQ 6",
+        "IRQ 7",
+        "IRQ 8",
+        "IRQ 9",
+        "IRQ 10",
+        "IRQ 11",
+        "IRQ 12",
+        "IRQ 13",
+        "IRQ 14",
+        "IRQ 15",
+    ];
+    match vector {
+        0..=31 => exception_name(vector),
+        SPURIOUS_VECTOR => "APIC SPURIOUS",
+        v if v >= PIC_1_OFFSET && v < PIC_1_OFFSET + IRQ_NUM as u8 => {
+            IRQ_NAMES[(v - PIC_1_OFFSET) as usize]
+        }
+        _ => "-",
+    }
+}
+
+// the vectors that fired at least once
+pub fn print_interrupt_stats() {
+    println!("vector name                          count spurious  total(ns)    avg(ns)");
+    for vector in 0..VECTOR_NUM {
+        let stats = vector_stats(vector as u8);
+        if stats.count == 0 {
+            continue;
+        }
+        println!(
+            "{:6} {:26} {:9} {:8} {:10} {:10}",
+            vector,
+            vector_name(vector as u8),
+            stats.count,
+            stats.spurious,
+            stats.total_nanos(),
+            stats.average_nanos()
+        );
+    }
+}
@@ -1,13 +1,19 @@
 use crate::println;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::VirtAddr;
 
+use crate::apic;
+use crate::exceptions;
 use crate::gdt;
 use crate::hlt_loop;
+use crate::interrupt_stats;
+use crate::irq;
 use lazy_static::lazy_static;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        exceptions::install(&mut idt);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
         unsafe {
             idt.double_fault
@@ -15,8 +21,8 @@ lazy_static! {
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
         idt.page_fault.set_handler_fn(page_fault_handler);
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        irq::install(&mut idt);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
@@ -26,6 +32,7 @@ pub fn init_idt() {
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
+    let _handler_timer = interrupt_stats::enter(exceptions::BREAKPOINT);
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
@@ -33,23 +40,50 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut InterruptStackFrame,
     _error_code: u64,
 ) {
+    use x86_64::registers::control::Cr2;
+
+    let _handler_timer = interrupt_stats::enter(exceptions::DOUBLE_FAULT);
     println!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    // a kernel stack overflow faults while pushing the page fault frame,
+    // which escalates to a double fault. CR2 still holds the guard page address
+    report_stack_overflow(Cr2::read());
     hlt_loop();
 }
 
+fn report_stack_overflow(addr: VirtAddr) {
+    use crate::kernel_stack::guard_page_owner;
+
+    if let Some(pid) = guard_page_owner(addr) {
+        println!("Kernel stack overflow in process {} at {:?}", pid, addr);
+    } else if gdt::is_double_fault_guard(addr) {
+        println!("Double fault stack overflow at {:?}", addr);
+    }
+}
+
 // NOTE: page fault handler
 use x86_64::structures::idt::PageFaultErrorCode;
 
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: &mut InterruptStackFrame,
-    _error_code: PageFaultErrorCode,
+    error_code: PageFaultErrorCode,
 ) {
+    use crate::page_fault::{handle_page_fault, PageFaultError};
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+    let _handler_timer = interrupt_stats::enter(exceptions::PAGE_FAULT);
+    let addr = Cr2::read();
+    // resolved faults return and restart the faulting instruction
+    if let Err(err) = handle_page_fault(addr, error_code) {
+        println!("EXCEPTION: PAGE FAULT");
+        println!("Accessed Address: {:?}", addr);
+        println!("Error Code: {:?}", error_code);
+        println!("Reason: {:?}", err);
+        if err == PageFaultError::StackGuard {
+            report_stack_overflow(addr);
+        }
+        println!("{:#?}", stack_frame);
+        hlt_loop();
+    }
 }
 
 // Hardware Interrupt implementation
@@ -61,61 +95,60 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
+// acknowledge a hardware interrupt at whichever controller delivered it
+pub fn end_of_interrupt(irq: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(irq::vector_of(irq)) };
+    }
 }
 
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
-    }
+// the PICs start with every line masked, the timer and keyboard are registered like any driver
+pub fn init_pics() {
+    unsafe { PICS.lock().initialize() };
+    irq::mask_all_pic_lines();
+    irq::register_irq_handler(irq::TIMER_IRQ, timer_interrupt)
+        .expect("cannot register the timer handler");
+    irq::register_irq_handler(irq::KEYBOARD_IRQ, keyboard_interrupt)
+        .expect("cannot register the keyboard handler");
+}
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
-    }
+// switch to the APICs if the machine has them, otherwise the PICs stay in charge
+pub fn init_apic() -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if !apic::init() {
+            return false;
+        }
+        irq::reroute_lines();
+        true
+    })
 }
 
 // NOTE: timer interrupt handler
-use crate::print;
-
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+fn timer_interrupt(_irq: u8) {
     // print!(".");
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    crate::time::tick();
+    crate::timer::schedule_expired();
+    crate::scheduler::tick();
 }
 
 // NOTE: keyboard interrupt handler
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
-    use spin::Mutex;
+// the scancode has to be read now, decoding and buffering the key can wait
+fn keyboard_interrupt(_irq: u8) {
     use x86_64::instructions::port::Port;
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1));
-    }
-
-    let mut keyboard = KEYBOARD.lock();
     let port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
+    crate::softirq::queue_work(decode_scancode, usize::from(scancode));
+}
 
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
-    }
+fn decode_scancode(scancode: usize) {
+    crate::keyboard::handle_scancode(scancode as u8);
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+// the local APIC raises it for interrupts that went away before they were delivered,
+// they must not be acknowledged
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    interrupt_stats::count_spurious(apic::SPURIOUS_VECTOR);
 }
@@ -0,0 +1,315 @@
+use crate::apic;
+use crate::interrupt_stats;
+use crate::interrupts::{end_of_interrupt, PIC_1_OFFSET};
+use crate::println;
+use crate::scheduler;
+use crate::softirq;
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+// NOTE: runtime registration of IRQ handlers
+//
+// Every legacy IRQ line has a fixed IDT entry (vector 32 + irq) that calls `dispatch`.
+// Drivers register plain functions for a line, several handlers may share one line and
+// are called in registration order; each one has to check whether its device raised the
+// interrupt. The end of interrupt is sent once after all handlers ran, then the work the
+// handlers deferred runs (see `softirq`).
+// A line is unmasked at the interrupt controller while it has at least one handler.
+pub const IRQ_NUM: usize = 16;
+pub const TIMER_IRQ: u8 = 0;
+pub const KEYBOARD_IRQ: u8 = 1;
+pub const MAX_SHARED_HANDLERS: usize = 4;
+// the slave PIC is cascaded on this line of the master
+const CASCADE_IRQ: u8 = 2;
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_2_DATA: u16 = 0xa1;
+// OCW3: the next read of the command port returns the in-service register
+const PIC_READ_ISR: u8 = 0x0b;
+const PIC_EOI: u8 = 0x20;
+
+pub type IrqHandler = fn(irq: u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidIrq,
+    // all `MAX_SHARED_HANDLERS` slots of the line are taken
+    LineFull,
+    NotRegistered,
+}
+
+// identifies a registered handler, needed to unregister it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandlerId {
+    irq: u8,
+    slot: usize,
+}
+
+impl IrqHandlerId {
+    pub fn get_irq(&self) -> u8 {
+        self.irq
+    }
+}
+
+type IrqLine = [Option<IrqHandler>; MAX_SHARED_HANDLERS];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IrqStats {
+    // interrupts received on the line
+    pub count: u64,
+    // interrupts nobody registered a handler for
+    pub unhandled: u64,
+    // work items the handlers of the line deferred
+    pub deferred: u64,
+}
+
+// only updated by the interrupt handlers, which run with interrupts disabled
+static mut IRQ_STATS: [IrqStats; IRQ_NUM] = [IrqStats {
+    count: 0,
+    unhandled: 0,
+    deferred: 0,
+}; IRQ_NUM];
+// the IRQ whose handlers are running
+static mut CURRENT_IRQ: Option<u8> = None;
+
+lazy_static! {
+    static ref IRQ_HANDLERS: Mutex<[IrqLine; IRQ_NUM]> =
+        Mutex::new([[None; MAX_SHARED_HANDLERS]; IRQ_NUM]);
+}
+
+pub fn vector_of(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
+}
+
+// the handlers are only changed with interrupts disabled,
+// so `dispatch` never finds the lock taken on this processor
+pub fn register_irq_handler(irq: u8, handler: IrqHandler) -> Result<IrqHandlerId, IrqError> {
+    if irq as usize >= IRQ_NUM {
+        return Err(IrqError::InvalidIrq);
+    }
+    without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let line = &mut handlers[irq as usize];
+        let slot = match line.iter().position(|handler| handler.is_none()) {
+            Some(slot) => slot,
+            None => return Err(IrqError::LineFull),
+        };
+        let was_unused = line.iter().all(|handler| handler.is_none());
+        line[slot] = Some(handler);
+        if was_unused {
+            enable_line(irq);
+        }
+        Ok(IrqHandlerId {
+            irq: irq,
+            slot: slot,
+        })
+    })
+}
+
+pub fn unregister_irq_handler(id: IrqHandlerId) -> Result<(), IrqError> {
+    without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let line = &mut handlers[id.irq as usize];
+        if line[id.slot].take().is_none() {
+            return Err(IrqError::NotRegistered);
+        }
+        if line.iter().all(|handler| handler.is_none()) {
+            disable_line(id.irq);
+        }
+        Ok(())
+    })
+}
+
+pub fn has_handlers(irq: u8) -> bool {
+    without_interrupts(|| match IRQ_HANDLERS.lock().get(irq as usize) {
+        Some(line) => line.iter().any(|handler| handler.is_some()),
+        None => false,
+    })
+}
+
+// route every line that has handlers again, after switching interrupt controllers
+pub fn reroute_lines() {
+    without_interrupts(|| {
+        let handlers = IRQ_HANDLERS.lock();
+        for (irq, line) in handlers.iter().enumerate() {
+            if line.iter().any(|handler| handler.is_some()) {
+                enable_line(irq as u8);
+            }
+        }
+    })
+}
+
+fn enable_line(irq: u8) {
+    if apic::is_enabled() {
+        apic::route_legacy_irq(irq, vector_of(irq));
+    } else {
+        set_pic_masked(irq, false);
+    }
+}
+
+fn disable_line(irq: u8) {
+    if apic::is_enabled() {
+        apic::mask_legacy_irq(irq);
+    } else if irq != CASCADE_IRQ {
+        set_pic_masked(irq, true);
+    }
+}
+
+fn set_pic_masked(irq: u8, masked: bool) {
+    let (port, bit) = if irq < 8 {
+        (PIC_1_DATA, irq)
+    } else {
+        (PIC_2_DATA, irq - 8)
+    };
+    let port: Port<u8> = Port::new(port);
+    unsafe {
+        let mask = port.read();
+        if masked {
+            port.write(mask | 1 << bit);
+        } else {
+            port.write(mask & !(1 << bit));
+        }
+    }
+    // a line of the slave PIC needs the cascade line of the master
+    if irq >= 8 && !masked {
+        set_pic_masked(CASCADE_IRQ, false);
+    }
+}
+
+pub fn irq_stats(irq: u8) -> Option<IrqStats> {
+    if irq as usize >= IRQ_NUM {
+        return None;
+    }
+    Some(without_interrupts(|| unsafe { IRQ_STATS[irq as usize] }))
+}
+
+pub fn current_irq() -> Option<u8> {
+    unsafe { CURRENT_IRQ }
+}
+
+// a handler of `irq` queued deferred work
+pub fn count_deferred(irq: u8) {
+    unsafe { IRQ_STATS[irq as usize].deferred += 1 };
+}
+
+pub fn print_irq_stats() {
+    println!("IRQ     count  unhandled   deferred");
+    for irq in 0..IRQ_NUM as u8 {
+        let stats = irq_stats(irq).unwrap();
+        if stats.count > 0 || has_handlers(irq) {
+            println!(
+                "{:3} {:9} {:10} {:10}",
+                irq, stats.count, stats.unhandled, stats.deferred
+            );
+        }
+    }
+}
+
+// with the PICs, IRQ 7 and IRQ 15 are also raised for requests that went away before
+// they were acknowledged, the in-service bit of the line is not set for those
+fn is_spurious_pic_irq(irq: u8) -> bool {
+    if apic::is_enabled() || (irq != 7 && irq != 15) {
+        return false;
+    }
+    let command: Port<u8> = Port::new(if irq == 7 { PIC_1_COMMAND } else { PIC_2_COMMAND });
+    unsafe {
+        command.write(PIC_READ_ISR);
+        command.read() & 0x80 == 0
+    }
+}
+
+fn dispatch(irq: u8) {
+    if is_spurious_pic_irq(irq) {
+        interrupt_stats::count_spurious(vector_of(irq));
+        // the master did take the cascade request of the slave, only the master gets an EOI
+        if irq == 15 {
+            let command: Port<u8> = Port::new(PIC_1_COMMAND);
+            unsafe { command.write(PIC_EOI) };
+        }
+        return;
+    }
+    let handler_timer = interrupt_stats::enter(vector_of(irq));
+    // copy the handlers out, a handler may (un)register handlers itself
+    let line = IRQ_HANDLERS.lock()[irq as usize];
+    unsafe {
+        IRQ_STATS[irq as usize].count += 1;
+        if line.iter().all(|handler| handler.is_none()) {
+            IRQ_STATS[irq as usize].unhandled += 1;
+        }
+        CURRENT_IRQ = Some(irq);
+    }
+    for handler in line.iter().filter_map(|handler| *handler) {
+        handler(irq);
+    }
+    unsafe { CURRENT_IRQ = None };
+    end_of_interrupt(irq);
+    drop(handler_timer);
+    softirq::run_pending();
+    // the interrupt is done, switching away here is like switching away from a yield
+    scheduler::preempt();
+}
+
+macro_rules! irq_handler {
+    ($name:ident, $irq:expr) => {
+        extern "x86-interrupt" fn $name(_stack_frame: &mut InterruptStackFrame) {
+            dispatch($irq);
+        }
+    };
+}
+
+irq_handler!(irq_0_handler, 0);
+irq_handler!(irq_1_handler, 1);
+irq_handler!(irq_2_handler, 2);
+irq_handler!(irq_3_handler, 3);
+irq_handler!(irq_4_handler, 4);
+irq_handler!(irq_5_handler, 5);
+irq_handler!(irq_6_handler, 6);
+irq_handler!(irq_7_handler, 7);
+irq_handler!(irq_8_handler, 8);
+irq_handler!(irq_9_handler, 9);
+irq_handler!(irq_10_handler, 10);
+irq_handler!(irq_11_handler, 11);
+irq_handler!(irq_12_handler, 12);
+irq_handler!(irq_13_handler, 13);
+irq_handler!(irq_14_handler, 14);
+irq_handler!(irq_15_handler, 15);
+
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    let handlers: [extern "x86-interrupt" fn(&mut InterruptStackFrame); IRQ_NUM] = [
+        irq_0_handler,
+        irq_1_handler,
+        irq_2_handler,
+        irq_3_handler,
+        irq_4_handler,
+        irq_5_handler,
+        irq_6_handler,
+        irq_7_handler,
+        irq_8_handler,
+        irq_9_handler,
+        irq_10_handler,
+        irq_11_handler,
+        irq_12_handler,
+        irq_13_handler,
+        irq_14_handler,
+        irq_15_handler,
+    ];
+    for (irq, &handler) in handlers.iter().enumerate() {
+        idt[usize::from(vector_of(irq as u8))].set_handler_fn(handler);
+    }
+}
+
+// mask every line at the PICs, lines are unmasked as handlers get registered
+pub fn mask_all_pic_lines() {
+    let pic_1: Port<u8> = Port::new(PIC_1_DATA);
+    let pic_2: Port<u8> = Port::new(PIC_2_DATA);
+    unsafe {
+        pic_1.write(0xff & !(1 << CASCADE_IRQ));
+        pic_2.write(0xff);
+    }
+}
@@ -0,0 +1,176 @@
+use crate::address_space::{active_l4_frame, update_leaf_flags};
+use crate::page_walker::{walk_ranges, DumpFilter};
+use crate::println;
+
+use alloc::vec::Vec;
+use core::ptr;
+use x86_64::structures::paging::{PageSize, PageTableFlags, Size4KiB};
+
+// NOTE: W^X for the kernel
+//
+// bootloader 0.6 does not tell us where the kernel sections are,
+// but the linker defines `__ehdr_start` at the ELF header, which is loaded
+// together with the first segment. The program headers tell us the permissions
+// of every loaded segment: text is read-execute, rodata read-only and data/bss
+// read-write no-execute.
+extern "C" {
+    static __ehdr_start: u8;
+}
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct ProgramHeader {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+fn kernel_program_headers() -> Vec<ProgramHeader> {
+    unsafe {
+        let ehdr = &__ehdr_start as *const u8;
+        assert_eq!(
+            ptr::read(ehdr as *const [u8; 4]),
+            *b"\x7fELF",
+            "kernel ELF header is not mapped"
+        );
+        // e_phoff, e_phentsize and e_phnum of an Elf64_Ehdr
+        let phoff = ptr::read_unaligned(ehdr.offset(32) as *const u64);
+        let phentsize = ptr::read_unaligned(ehdr.offset(54) as *const u16);
+        let phnum = ptr::read_unaligned(ehdr.offset(56) as *const u16);
+
+        (0..phnum as isize)
+            .map(|i| {
+                let header = ehdr.offset(phoff as isize + i * phentsize as isize);
+                ptr::read_unaligned(header as *const ProgramHeader)
+            })
+            .collect()
+    }
+}
+
+// a loaded segment rounded out to whole pages
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    start: u64,
+    end: u64,
+    writable: bool,
+    executable: bool,
+}
+
+// the loaded segments in ascending address order, as the ELF spec requires
+// `linker.ld` starts every segment on a new page. should two segments still share a page,
+// the page goes to a segment of its own that is not W+X: writable if either needs to write,
+// then the code on it must not run (it faults, instead of the kernel being W+X)
+fn kernel_segments() -> Vec<Segment> {
+    let page_size = Size4KiB::SIZE;
+    let mut segments: Vec<Segment> = Vec::new();
+    for header in kernel_program_headers() {
+        if header.p_type != PT_LOAD || header.p_memsz == 0 {
+            continue;
+        }
+        let mut segment = Segment {
+            start: header.p_vaddr & !(page_size - 1),
+            end: (header.p_vaddr + header.p_memsz + page_size - 1) & !(page_size - 1),
+            writable: header.p_flags & PF_W != 0,
+            executable: header.p_flags & PF_X != 0,
+        };
+        let shared = match segments.last_mut() {
+            Some(last) if last.end > segment.start => {
+                println!("kernel segments share the page at {:#x}", segment.start);
+                let writable = last.writable || segment.writable;
+                let shared = Segment {
+                    start: segment.start,
+                    end: last.end.min(segment.end),
+                    writable: writable,
+                    executable: (last.executable || segment.executable) && !writable,
+                };
+                last.end = shared.start;
+                segment.start = shared.end;
+                Some(shared)
+            }
+            _ => None,
+        };
+        if let Some(shared) = shared {
+            if segments.last().map_or(false, |last| last.start == last.end) {
+                segments.pop();
+            }
+            segments.push(shared);
+        }
+        if segment.start < segment.end {
+            segments.push(segment);
+        }
+    }
+    segments
+}
+
+fn remap_kernel_image() {
+    let l4_frame = active_l4_frame();
+    for segment in kernel_segments() {
+        update_leaf_flags(l4_frame, segment.start, segment.end, &mut |_, flags| {
+            let mut flags = flags;
+            flags.set(PageTableFlags::WRITABLE, segment.writable);
+            flags.set(PageTableFlags::NO_EXECUTE, !segment.executable);
+            flags
+        });
+    }
+}
+
+pub fn enable_nxe() {
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+    unsafe { Efer::update(|efer| *efer |= EferFlags::NO_EXECUTE_ENABLE) };
+}
+
+// must be called after the heap and `address_space::init` are set up
+pub fn init(physical_memory_offset: u64, max_phys_addr: u64) {
+    // the bootloader already enables it, but we rely on it from now on
+    enable_nxe();
+    remap_kernel_image();
+
+    let l4_frame = active_l4_frame();
+    // the complete physical memory mapping is data only
+    let direct_map_end = physical_memory_offset + max_phys_addr;
+    update_leaf_flags(l4_frame, physical_memory_offset, direct_map_end, &mut |_, flags| {
+        flags | PageTableFlags::NO_EXECUTE
+    });
+
+    // whatever else is still writable and executable (e.g. the bootloader's identity mapping)
+    // is never executed by the kernel, only kernel text may stay executable
+    let segments = kernel_segments();
+    let is_text = |virt: u64| {
+        segments
+            .iter()
+            .any(|segment| segment.executable && segment.start <= virt && virt < segment.end)
+    };
+    update_leaf_flags(l4_frame, 0, u64::max_value(), &mut |virt, flags| {
+        let writable_executable = flags.contains(PageTableFlags::WRITABLE)
+            && !flags.contains(PageTableFlags::NO_EXECUTE);
+        if writable_executable && !is_text(virt) {
+            flags | PageTableFlags::NO_EXECUTE
+        } else {
+            flags
+        }
+    });
+    crate::tlb::flush_all();
+
+    audit();
+}
+
+// panic if any page is both writable and executable
+pub fn audit() {
+    let mut range_num = 0;
+    walk_ranges(active_l4_frame(), DumpFilter::WRITABLE_EXECUTABLE, &mut |range| {
+        println!("W+X: {}", range);
+        range_num += 1;
+    });
+    if range_num > 0 {
+        panic!("{} writable and executable mappings found", range_num);
+    }
+}
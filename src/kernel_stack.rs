@@ -0,0 +1,159 @@
+use crate::frame_allocator::get_frame_allocator;
+use crate::memory;
+use crate::page_fault::{register_kernel_vma, unregister_kernel_vma};
+use crate::tlb::TlbBatch;
+use crate::vma::{Vma, VmaBacking, VmaFlags};
+use crate::PHYSICAL_MEMORY_OFFSET;
+
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{
+    FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+// NOTE: kernel stacks live in their own part of the kernel half
+// every slot is a guard page followed by the stack pages:
+//
+//   | guard | stack ... stack | guard | stack ... stack | ...
+//
+// the guard page is never mapped, so an overflow faults instead of
+// silently running into whatever is below the stack
+pub const KERNEL_STACK_AREA_START: u64 = 0x_ffff_a000_0000_0000;
+pub const KERNEL_STACK_PAGES: u64 = 4;
+const SLOT_PAGES: u64 = KERNEL_STACK_PAGES + 1;
+pub const MAX_KERNEL_STACKS: usize = 4096;
+
+// the pid owning each slot, 0 if the slot is free (the kernel process has no kernel stack)
+// only changed with interrupts disabled, the page fault handler reads it
+static mut STACK_OWNERS: [usize; MAX_KERNEL_STACKS] = [0; MAX_KERNEL_STACKS];
+
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: usize,
+    // lowest usable address, right above the guard page
+    bottom: VirtAddr,
+    top: VirtAddr,
+}
+
+fn slot_start(slot: usize) -> VirtAddr {
+    VirtAddr::new(KERNEL_STACK_AREA_START + slot as u64 * SLOT_PAGES * Size4KiB::SIZE)
+}
+
+fn take_free_slot(owner: usize) -> Option<usize> {
+    assert!(owner != 0, "the kernel process has no kernel stack");
+    interrupts::without_interrupts(|| unsafe {
+        let slot = STACK_OWNERS.iter().position(|&owner| owner == 0)?;
+        STACK_OWNERS[slot] = owner;
+        Some(slot)
+    })
+}
+
+fn release_slot(slot: usize) {
+    interrupts::without_interrupts(|| unsafe { STACK_OWNERS[slot] = 0 });
+}
+
+impl KernelStack {
+    // allocate and map a stack for process `owner`
+    pub fn new(owner: usize) -> Option<Self> {
+        let slot = take_free_slot(owner)?;
+        let guard = slot_start(slot);
+        let bottom = guard + Size4KiB::SIZE;
+        let top = bottom + KERNEL_STACK_PAGES * Size4KiB::SIZE;
+
+        let guard_vma = Vma::new(
+            guard.as_u64(),
+            bottom.as_u64(),
+            VmaFlags::empty(),
+            VmaBacking::StackGuard,
+        );
+        if register_kernel_vma(guard_vma).is_err() {
+            release_slot(slot);
+            return None;
+        }
+
+        let stack = KernelStack {
+            slot: slot,
+            bottom: bottom,
+            top: top,
+        };
+        // the stack pages are mapped eagerly,
+        // a fault while pushing the exception frame would be a double fault anyway
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let frame_allocator = get_frame_allocator();
+        let mut mapper = unsafe { memory::init(PHYSICAL_MEMORY_OFFSET as u64) };
+        for page in stack.pages() {
+            // `stack` is dropped on failure, which unmaps what was mapped so far
+            let frame = frame_allocator.alloc_zeroed_frame()?;
+            let map_to_result = unsafe { mapper.map_to(page, frame, flags, frame_allocator) };
+            match map_to_result {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    frame_allocator.deallocate_frame(frame);
+                    return None;
+                }
+            }
+            if let Some(frame_info) = frame_allocator.frame_info(frame) {
+                frame_info.inc_count();
+            }
+        }
+        Some(stack)
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let start: Page<Size4KiB> = Page::containing_address(self.bottom);
+        let end: Page<Size4KiB> = Page::containing_address(self.top);
+        Page::range(start, end)
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    pub fn size(&self) -> u64 {
+        self.top - self.bottom
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let frame_allocator = get_frame_allocator();
+        let mut mapper = unsafe { memory::init(PHYSICAL_MEMORY_OFFSET as u64) };
+        let mut batch = TlbBatch::new();
+        for page in self.pages() {
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                batch.add_flush(page, flush);
+                if let Some(frame_info) = frame_allocator.frame_info(frame) {
+                    frame_info.dec_count();
+                }
+                frame_allocator.deallocate_frame(frame);
+            }
+        }
+        // the TLB must forget the stack before the slot can be reused
+        batch.flush();
+        let guard = slot_start(self.slot);
+        let _ = unregister_kernel_vma(guard, self.bottom);
+        release_slot(self.slot);
+    }
+}
+
+// if `addr` is inside the guard page of a kernel stack, the pid owning that stack
+pub fn guard_page_owner(addr: VirtAddr) -> Option<usize> {
+    let addr = addr.as_u64();
+    if addr < KERNEL_STACK_AREA_START {
+        return None;
+    }
+    let offset = addr - KERNEL_STACK_AREA_START;
+    let slot = (offset / (SLOT_PAGES * Size4KiB::SIZE)) as usize;
+    let in_guard = offset % (SLOT_PAGES * Size4KiB::SIZE) < Size4KiB::SIZE;
+    if !in_guard {
+        return None;
+    }
+    match unsafe { STACK_OWNERS.get(slot) } {
+        Some(&owner) if owner != 0 => Some(owner),
+        _ => None,
+    }
+}
@@ -0,0 +1,213 @@
+use crate::data_structures::RingBuffer;
+use crate::print;
+use crate::scheduler;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, DecodedKey, KeyCode, KeyState, Keyboard, ScancodeSet1};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// NOTE: buffered keyboard input
+//
+// The keyboard interrupt only reads the scancode, the deferred work decodes it and pushes
+// the key event into a ring buffer, nothing is printed in interrupt context. Readers take
+// the events out with `read_key`, `read_char` or `read_line` and block while the buffer
+// is empty, the deferred work wakes the waiting reader up.
+// Events that arrive while the buffer is full are dropped.
+
+bitflags! {
+    pub struct Modifiers: u8 {
+        const SHIFT = 0x1;
+        const CTRL = 0x2;
+        const ALT = 0x4;
+        const CAPS_LOCK = 0x8;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    // the modifiers after this event was applied
+    pub modifiers: Modifiers,
+    // the character typed, only for key down events of printable keys
+    pub character: Option<char>,
+}
+
+impl KeyEvent {
+    pub fn is_down(&self) -> bool {
+        self.state == KeyState::Down
+    }
+}
+
+impl Default for KeyEvent {
+    fn default() -> Self {
+        KeyEvent {
+            code: KeyCode::Escape,
+            state: KeyState::Up,
+            modifiers: Modifiers::empty(),
+            character: None,
+        }
+    }
+}
+
+struct KeyboardState {
+    decoder: Keyboard<layouts::Us104Key, ScancodeSet1>,
+    // the left and right keys are tracked separately, releasing one keeps the modifier
+    shift: [bool; 2],
+    ctrl: [bool; 2],
+    alt: [bool; 2],
+    caps_lock: bool,
+}
+
+impl KeyboardState {
+    fn update_modifiers(&mut self, code: KeyCode, state: KeyState) {
+        let down = state == KeyState::Down;
+        match code {
+            KeyCode::ShiftLeft => self.shift[0] = down,
+            KeyCode::ShiftRight => self.shift[1] = down,
+            KeyCode::ControlLeft => self.ctrl[0] = down,
+            KeyCode::ControlRight => self.ctrl[1] = down,
+            KeyCode::AltLeft => self.alt[0] = down,
+            KeyCode::AltRight => self.alt[1] = down,
+            KeyCode::CapsLock if down => self.caps_lock = !self.caps_lock,
+            _ => (),
+        }
+    }
+
+    fn modifiers(&self) -> Modifiers {
+        let mut modifiers = Modifiers::empty();
+        modifiers.set(Modifiers::SHIFT, self.shift[0] || self.shift[1]);
+        modifiers.set(Modifiers::CTRL, self.ctrl[0] || self.ctrl[1]);
+        modifiers.set(Modifiers::ALT, self.alt[0] || self.alt[1]);
+        modifiers.set(Modifiers::CAPS_LOCK, self.caps_lock);
+        modifiers
+    }
+}
+
+lazy_static! {
+    // only locked by the deferred keyboard work, which never runs nested
+    static ref KEYBOARD: Mutex<KeyboardState> = Mutex::new(KeyboardState {
+        decoder: Keyboard::new(layouts::Us104Key, ScancodeSet1),
+        shift: [false; 2],
+        ctrl: [false; 2],
+        alt: [false; 2],
+        caps_lock: false,
+    });
+    static ref KEY_EVENTS: RingBuffer<KeyEvent> = RingBuffer::new();
+    // the ring buffer has a single consumer, readers take turns for each pop
+    static ref READER: Mutex<()> = Mutex::new(());
+    // the readers blocked in `wait_for_key`, only locked with interrupts disabled
+    static ref KEY_WAITERS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+}
+
+static DROPPED_EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+// called by the deferred keyboard work with the byte read from the controller
+pub fn handle_scancode(scancode: u8) {
+    let mut keyboard = KEYBOARD.lock();
+    let key_event = match keyboard.decoder.add_byte(scancode) {
+        Ok(Some(key_event)) => key_event,
+        _ => return,
+    };
+    let (code, state) = (key_event.code, key_event.state);
+    keyboard.update_modifiers(code, state);
+    let character = match keyboard.decoder.process_keyevent(key_event) {
+        Some(DecodedKey::Unicode(character)) if state == KeyState::Down => Some(character),
+        _ => None,
+    };
+    let event = KeyEvent {
+        code: code,
+        state: state,
+        modifiers: keyboard.modifiers(),
+        character: character,
+    };
+    if !KEY_EVENTS.push(event) {
+        DROPPED_EVENTS.fetch_add(1, Ordering::Relaxed);
+    }
+    // every reader tries again, those who come too late block again
+    interrupts::without_interrupts(|| {
+        for waiter in KEY_WAITERS.lock().drain(..) {
+            scheduler::wake(waiter);
+        }
+    });
+}
+
+pub fn dropped_events() -> usize {
+    DROPPED_EVENTS.load(Ordering::Relaxed)
+}
+
+// block until the next key event unless one is already waiting, checking and blocking
+// with interrupts disabled so the wakeup cannot be missed
+// before the scheduler runs there is nobody to switch to, the CPU halts instead
+fn wait_for_key() {
+    interrupts::disable();
+    if !KEY_EVENTS.is_empty() {
+        interrupts::enable();
+    } else if scheduler::is_initialized() {
+        let pid = scheduler::active_pid();
+        {
+            let mut waiters = KEY_WAITERS.lock();
+            if !waiters.contains(&pid) {
+                waiters.push(pid);
+            }
+        }
+        scheduler::block();
+        interrupts::enable();
+    } else {
+        // `sti` only takes effect after the next instruction, so nothing fires in between
+        unsafe { asm!("sti; hlt" :::: "volatile") };
+    }
+}
+
+pub fn try_read_key() -> Option<KeyEvent> {
+    let _reader = READER.lock();
+    KEY_EVENTS.pop()
+}
+
+// the next key event, key up events included
+// the reader lock is only held for the pop, never while blocked
+pub fn read_key() -> KeyEvent {
+    loop {
+        if let Some(event) = try_read_key() {
+            return event;
+        }
+        wait_for_key();
+    }
+}
+
+// the next character typed
+pub fn read_char() -> char {
+    loop {
+        if let Some(character) = read_key().character {
+            return character;
+        }
+    }
+}
+
+// read up to the end of the line, the line is echoed and can be edited with backspace
+// the newline is not part of the returned string
+pub fn read_line() -> String {
+    let mut line = String::new();
+    loop {
+        match read_char() {
+            '\n' => {
+                print!("\n");
+                return line;
+            }
+            '\u{8}' => {
+                if line.pop().is_some() {
+                    print!("\u{8}");
+                }
+            }
+            character => {
+                line.push(character);
+                print!("{}", character);
+            }
+        }
+    }
+}
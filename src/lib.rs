@@ -5,23 +5,47 @@
 
 #[macro_use]
 extern crate alloc;
+pub mod acpi;
+pub mod address_space;
+pub mod apic;
 pub mod data_structures;
+pub mod exceptions;
 pub mod frame_allocator;
 pub mod gdt;
+pub mod interrupt_stats;
 pub mod interrupts;
+pub mod irq;
+pub mod keyboard;
+pub mod kernel_protection;
+pub mod kernel_stack;
 
 pub mod context;
 pub mod process;
 pub mod vga_buffer;
 pub mod vm;
+pub mod vma;
+pub mod vmalloc;
 pub mod memory;
+pub mod page_fault;
+pub mod page_walker;
+pub mod sched_policy;
+pub mod scheduler;
+pub mod shared_memory;
+pub mod softirq;
+pub mod swap;
+pub mod thread;
+pub mod time;
+pub mod timer;
+pub mod tlb;
+pub mod tsc;
 
 pub static mut PHYSICAL_MEMORY_OFFSET: usize = 0;
 
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
-    unsafe { interrupts::PICS.lock().initialize() };
+    interrupts::init_pics();
+    time::init();
     x86_64::instructions::interrupts::enable();
 }
 
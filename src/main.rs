@@ -2,6 +2,7 @@
 #![cfg_attr(not(test), no_main)]
 #![cfg_attr(test, allow(unused_imports))]
 #![feature(alloc_error_handler)]
+#![feature(asm)]
 
 use core::panic::PanicInfo;
 use yzos::{print, println};
@@ -21,6 +22,7 @@ fn panic(_info: &PanicInfo) -> ! {
 extern crate alloc;
 
 use alloc::alloc::{GlobalAlloc, Layout};
+use x86_64::instructions::interrupts::without_interrupts;
 use yzos::vm::KernelHeapAllocator;
 
 // we need a wrapper here
@@ -45,14 +47,18 @@ impl KernelHeapAllocatorWrap {
 unsafe impl Sync for KernelHeapAllocatorWrap {}
 unsafe impl Send for KernelHeapAllocatorWrap {}
 
+// NOTE: the heap has no lock, so the allocator runs with interrupts disabled
+// deferred interrupt work (e.g. the timer callbacks) allocates, and a process must not be
+// preempted in the middle of an allocation either
 unsafe impl GlobalAlloc for KernelHeapAllocatorWrap {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let ptr = (*self.kernel_heap_allocator).malloc(layout);
-        ptr
+        let heap_allocator = self.kernel_heap_allocator;
+        without_interrupts(|| (*heap_allocator).malloc(layout))
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        (*self.kernel_heap_allocator).free(ptr, layout);
+        let heap_allocator = self.kernel_heap_allocator;
+        without_interrupts(|| (*heap_allocator).free(ptr, layout));
     }
 }
 
@@ -72,9 +78,10 @@ fn alloc_error_handler(layout: Layout) -> ! {
 
 use bootloader::{entry_point, BootInfo};
 
+use yzos::address_space;
 use yzos::memory;
 
-use yzos::frame_allocator::SimpleFrameAllocator;
+use yzos::frame_allocator::{self, SimpleFrameAllocator};
 use yzos::PHYSICAL_MEMORY_OFFSET;
 
 entry_point!(kernel_main);
@@ -97,68 +104,254 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut heap_allocator = KernelHeapAllocator::new(frame_allocator, 1024);
 
     unsafe { GLOBAL_ALLOCATOR.init(&mut heap_allocator) };
+    frame_allocator::set_frame_allocator(heap_allocator.get_frame_allocator());
+    address_space::init();
+    yzos::gdt::protect_double_fault_stack();
+
+    let max_phys_addr = boot_info
+        .memory_map
+        .iter()
+        .map(|r| r.range.end_addr())
+        .max()
+        .unwrap_or(0);
+    yzos::kernel_protection::init(boot_info.physical_memory_offset, max_phys_addr);
+    yzos::tlb::init();
+    yzos::timer::init();
+    // 4MB of swap space
+    let swap_device = yzos::swap::RamDisk::new(1024).expect("out of memory for the swap device");
+    yzos::swap::init(Box::new(swap_device));
 
     println!("finished memory initialization");
 
+    if yzos::interrupts::init_apic() {
+        println!("interrupts are delivered by the APIC");
+    } else {
+        println!("no APIC found, interrupts are delivered by the PIC");
+    }
+    match yzos::tsc::init() {
+        Some(khz) => println!(
+            "TSC runs at {} kHz (calibrated with {:?}, invariant: {})",
+            khz,
+            yzos::tsc::calibration_source().unwrap(),
+            yzos::tsc::is_invariant()
+        ),
+        None => println!("TSC calibration failed, timestamps come from the PIT"),
+    }
+    yzos::scheduler::init();
+
     // test_linked_list();
     test_box();
     test_vec();
-    // test_process();
+    test_demand_paging();
+    test_cow_clone();
+    test_process_isolation();
+    test_process_teardown();
+    test_pcid_switch();
+    test_shared_memory();
+    test_swap();
+    test_vmalloc();
+    test_irq_handlers();
+    test_time();
+    test_tsc();
+    test_timers();
+    test_deferred_work();
+    test_interrupt_stats();
+    test_overcommit();
+    test_process();
+    test_spawn();
+    test_priorities();
+    test_exit();
 
     println!("It did not crash!");
 
-    yzos::hlt_loop();
+    // echo whatever is typed
+    loop {
+        yzos::keyboard::read_line();
+    }
 }
 
 // ==============================
 // NOTE: some test functions
 // ==============================
 
+// NOTE: three processes share the CPU, two of them never yield and are preempted by the
+// timer, the third yields after every burst. their bursts have to interleave
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use yzos::process::Process;
-use yzos::context::Context;
-static mut process1: *mut Process = core::ptr::null_mut();
-static mut process2: *mut Process = core::ptr::null_mut();
+use yzos::scheduler;
+use yzos::thread;
+
+const BURST_NUM: usize = 5;
+// the pid of the process that ran the previous burst
+static LAST_BURST_PID: AtomicUsize = AtomicUsize::new(0);
+static INTERLEAVED_BURSTS: AtomicUsize = AtomicUsize::new(0);
 
 #[allow(dead_code)]
 fn test_process() {
-    // let kernel_context = Context::save_current_context();
-    let stack1 = vec![0_u8; 4096];
-    let stack2 = vec![0_u8; 4096];
-
-    let p1 = Box::new(Process::new(stack1));
-    // p1.set_context(tfunction1 as *const fn());
-    unsafe { process1 = Box::into_raw(p1) };
-    let p2 = Box::new(Process::new(stack2));
-    // p2.set_context(tfunction1 as *const fn());
-    unsafe { process2 = Box::into_raw(p2) };
+    let switches = scheduler::switch_num();
+    let tfunctions: [fn(); 3] = [tfunction1, tfunction2, tfunction3];
+    let threads: Vec<_> = tfunctions.iter().map(|&tfunction| thread::spawn(tfunction)).collect();
+    for thread in threads {
+        assert_eq!(thread.join(), Ok(()));
+    }
+    // run one after the other, the processes would only change hands twice
+    assert!(INTERLEAVED_BURSTS.load(Ordering::SeqCst) > tfunctions.len());
+    println!(
+        "processes interleaved, {} context switches",
+        scheduler::switch_num() - switches
+    );
 }
 
-fn pass_on_CPU(process: *mut Process) {
-    let next_process = unsafe { &mut *process };
-    Process::dispatch_to(next_process);
+fn run_burst(name: &str, burst: usize) {
+    use core::time::Duration;
+    use yzos::time::busy_wait;
+
+    let pid = Process::get_active_process().get_pid();
+    println!("{} (process {}) IN BURST [{}]", name, pid, burst);
+    if LAST_BURST_PID.swap(pid, Ordering::SeqCst) != pid {
+        INTERLEAVED_BURSTS.fetch_add(1, Ordering::SeqCst);
+    }
+    // longer than a time slice, so the process is preempted in the middle of it
+    busy_wait(Duration::from_millis(15));
 }
 
-// here tfunction1 and tfunction2 only test
+// tfunction1 and tfunction2 never give up the CPU
 fn tfunction1() {
     println!("Process {} is running", Process::get_active_process().get_pid());
-    for i in 0..10 {
-        println!("FUN 1 IN BURST [{}]", i);
-        for j in 0..10 {
-            println!("FUN 1: TICK [{}]", j);
-        }
-        unsafe { pass_on_CPU(process2) };
+    for i in 0..BURST_NUM {
+        run_burst("FUN 1", i);
     }
 }
 
 fn tfunction2() {
     println!("Process {} is running", Process::get_active_process().get_pid());
-    for i in 0..10 {
-        println!("FUN 2 IN BURST [{}]", i);
-        for j in 0..10 {
-            println!("FUN 2: TICK [{}]", j);
+    for i in 0..BURST_NUM {
+        run_burst("FUN 2", i);
+    }
+}
+
+fn tfunction3() {
+    println!("Process {} is running", Process::get_active_process().get_pid());
+    for i in 0..BURST_NUM {
+        run_burst("FUN 3", i);
+        scheduler::yield_now();
+    }
+}
+
+// NOTE: threads get what their closure captured and hand back what it returned,
+// joining a thread that is already done returns right away
+#[allow(dead_code)]
+fn test_spawn() {
+    let threads: Vec<_> = (0..4usize)
+        .map(|i| {
+            let numbers: Vec<usize> = (0..=i * 100).collect();
+            thread::spawn(move || numbers.iter().sum::<usize>())
+        })
+        .collect();
+    for (i, thread) in threads.into_iter().enumerate() {
+        let n = i * 100;
+        assert_eq!(thread.join(), Ok(n * (n + 1) / 2));
+    }
+
+    let thread = thread::spawn(|| Box::new(42));
+    while !thread.is_finished() {
+        scheduler::yield_now();
+    }
+    assert_eq!(*thread.join().unwrap(), 42);
+    println!("spawn and join work");
+}
+
+// NOTE: two CPU-bound processes count as fast as they can for a while,
+// the one with the higher priority and the lower nice value has to get further
+static STOP_COUNTING: AtomicBool = AtomicBool::new(false);
+
+#[allow(dead_code)]
+fn test_priorities() {
+    use core::time::Duration;
+    use yzos::sched_policy::{MLFQ_LEVELS, NICE_MAX, NICE_MIN};
+    use yzos::time::sleep;
+
+    let high = thread::spawn(count);
+    let low = thread::spawn(count);
+    assert!(scheduler::set_nice(high.get_pid(), NICE_MIN));
+    assert!(scheduler::set_priority(low.get_pid(), MLFQ_LEVELS - 1));
+    assert!(scheduler::set_nice(low.get_pid(), NICE_MAX));
+    assert!(!scheduler::set_nice(usize::max_value(), 0));
+
+    sleep(Duration::from_millis(100));
+    STOP_COUNTING.store(true, Ordering::SeqCst);
+    let high_count = high.join().unwrap();
+    let low_count = low.join().unwrap();
+    assert!(high_count > low_count);
+    println!("priorities work, counted to {} and {}", high_count, low_count);
+}
+
+fn count() -> usize {
+    let mut count = 0;
+    while !STOP_COUNTING.load(Ordering::SeqCst) {
+        count += 1;
+    }
+    count
+}
+
+// NOTE: a thread exiting early hands its exit code to the joiner and to its parent,
+// once the reaper freed it every frame it took is free again.
+// an orphan is handed to the kernel process, which collects its exit code instead
+static ORPHAN_MAY_EXIT: AtomicBool = AtomicBool::new(false);
+
+#[allow(dead_code)]
+fn test_exit() {
+    use yzos::frame_allocator::get_frame_allocator;
+    use yzos::process::exit_active_process;
+
+    // the reaper runs once the kernel process gives up the CPU
+    let reap_all = || {
+        while scheduler::zombie_num() > 0 {
+            scheduler::yield_now();
         }
-        unsafe { pass_on_CPU(process1) };
+    };
+    reap_all();
+    let free_frames = get_frame_allocator().free_frame_num();
+
+    let thread = thread::spawn(|| -> usize { exit_active_process(7) });
+    let pid = thread.get_pid();
+    assert_eq!(scheduler::wait(pid), Some(7));
+    assert_eq!(scheduler::wait(pid), None);
+    assert_eq!(thread.join(), Err(7));
+
+    // joining collects the exit code
+    let thread = thread::spawn(|| -> usize { exit_active_process(8) });
+    let pid = thread.get_pid();
+    assert_eq!(thread.join(), Err(8));
+    assert_eq!(scheduler::wait(pid), None);
+
+    let parent = thread::spawn(|| {
+        // detached, it outlives its parent
+        let orphan = thread::spawn(|| {
+            while !ORPHAN_MAY_EXIT.load(Ordering::SeqCst) {
+                scheduler::yield_now();
+            }
+            exit_active_process(9);
+        });
+        orphan.get_pid()
+    });
+    let orphan_pid = parent.join().unwrap();
+    let detached = thread::spawn(|| -> usize { exit_active_process(10) });
+    let detached_pid = detached.get_pid();
+    drop(detached);
+    ORPHAN_MAY_EXIT.store(true, Ordering::SeqCst);
+    // nobody collects their exit codes, the pids go away with them.
+    // `set_nice` fails once a process exited
+    while scheduler::set_nice(orphan_pid, 0) || scheduler::set_nice(detached_pid, 0) {
+        scheduler::yield_now();
     }
+    assert_eq!(scheduler::wait(orphan_pid), None);
+    assert!(Process::get_active_process().exited_children.is_empty());
+
+    reap_all();
+    assert_eq!(get_frame_allocator().free_frame_num(), free_frames);
+    println!("exited processes are reaped");
 }
 
 use alloc::boxed::Box;
@@ -179,6 +372,487 @@ fn test_vec() {
     println!("");
 }
 
+// NOTE: touch a lazily-backed region, each page is mapped by the page fault handler
+#[allow(dead_code)]
+fn test_demand_paging() {
+    use yzos::page_fault::register_lazy_region;
+    use yzos::vma::VmaFlags;
+
+    let start = VirtAddr::new(0x_ffff_9000_0000_0000);
+    let end = start + 4 * 4096u64;
+    register_lazy_region(start, end, VmaFlags::READ | VmaFlags::WRITE)
+        .expect("register_lazy_region failed");
+
+    let ptr: *mut u64 = start.as_mut_ptr();
+    for i in 0..4 {
+        unsafe {
+            let page_ptr = ptr.offset(i * 512);
+            // freshly faulted pages must be zeroed
+            assert_eq!(page_ptr.read_volatile(), 0);
+            page_ptr.write_volatile(i as u64 + 42);
+        }
+    }
+    for i in 0..4 {
+        assert_eq!(unsafe { ptr.offset(i * 512).read_volatile() }, i as u64 + 42);
+    }
+    println!("demand paging works");
+}
+
+// NOTE: clone an address space with a user page, then write to it on one side
+#[allow(dead_code)]
+fn test_cow_clone() {
+    use x86_64::structures::paging::PageTableFlags;
+    use yzos::address_space::{
+        active_l4_frame, clone_address_space, destroy_address_space, frame_ptr, leaf_entry,
+        map_user_page,
+    };
+
+    let addr = VirtAddr::new(address_space::USER_SPACE_START);
+    let page = Page::containing_address(addr);
+    let parent = active_l4_frame();
+    map_user_page(parent, page, PageTableFlags::WRITABLE).expect("map_user_page failed");
+
+    let ptr: *mut u64 = addr.as_mut_ptr();
+    unsafe { ptr.write_volatile(1) };
+
+    let child = clone_address_space(parent).expect("clone failed");
+    // this write faults and gets a private copy
+    unsafe { ptr.write_volatile(2) };
+
+    let child_frame = leaf_entry(child, addr).unwrap().frame().unwrap();
+    let parent_frame = leaf_entry(parent, addr).unwrap().frame().unwrap();
+    assert_ne!(child_frame, parent_frame);
+    assert_eq!(unsafe { (frame_ptr(child_frame) as *const u64).read_volatile() }, 1);
+    assert_eq!(unsafe { ptr.read_volatile() }, 2);
+    destroy_address_space(child);
+    println!("copy-on-write works");
+}
+
+// NOTE: the same user address in two processes is backed by different frames
+#[allow(dead_code)]
+fn test_process_isolation() {
+    use x86_64::registers::control::{Cr3, Cr3Flags};
+    use x86_64::structures::paging::PageTableFlags;
+    use yzos::address_space::{active_l4_frame, map_user_page, USER_SPACE_START};
+
+    let p1 = Process::new();
+    let p2 = Process::new();
+
+    let addr = VirtAddr::new(USER_SPACE_START);
+    let page = Page::containing_address(addr);
+    map_user_page(p1.get_page_table(), page, PageTableFlags::WRITABLE).expect("map_user_page failed");
+    map_user_page(p2.get_page_table(), page, PageTableFlags::WRITABLE).expect("map_user_page failed");
+
+    let kernel_l4 = active_l4_frame();
+    let ptr: *mut u64 = addr.as_mut_ptr();
+    unsafe {
+        Cr3::write(p1.get_page_table(), Cr3Flags::empty());
+        ptr.write_volatile(1);
+        Cr3::write(p2.get_page_table(), Cr3Flags::empty());
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(2);
+        Cr3::write(p1.get_page_table(), Cr3Flags::empty());
+        assert_eq!(ptr.read_volatile(), 1);
+        Cr3::write(kernel_l4, Cr3Flags::empty());
+    }
+    println!("process address spaces are isolated");
+}
+
+// NOTE: create and destroy lots of processes, no frame may be leaked
+#[allow(dead_code)]
+fn test_process_teardown() {
+    use x86_64::structures::paging::PageTableFlags;
+    use yzos::address_space::{map_user_page, USER_SPACE_START};
+    use yzos::frame_allocator::get_frame_allocator;
+
+    let user_page = |i: u64| Page::containing_address(VirtAddr::new(USER_SPACE_START + i * 4096));
+    // the first process may grow kernel data structures (e.g. the kernel stack slots),
+    // which are reused afterwards
+    drop(Process::new());
+    let free_frames = get_frame_allocator().free_frame_num();
+
+    for _ in 0..4096 {
+        let parent = Process::new();
+        for i in 0..4 {
+            map_user_page(parent.get_page_table(), user_page(i), PageTableFlags::WRITABLE)
+                .expect("map_user_page failed");
+        }
+        // a page far away needs its own set of tables
+        map_user_page(parent.get_page_table(), user_page(1 << 27), PageTableFlags::empty())
+            .expect("map_user_page failed");
+        let child = parent.fork_address_space().expect("fork failed");
+        drop(parent);
+        drop(child);
+    }
+    assert_eq!(get_frame_allocator().free_frame_num(), free_frames);
+    println!("process teardown frees every frame");
+}
+
+// NOTE: change the page table of a process while it is not running,
+// switching back to it must not use the TLB entries cached under its PCID
+#[allow(dead_code)]
+fn test_pcid_switch() {
+    use x86_64::registers::control::{Cr3, Cr3Flags};
+    use x86_64::structures::paging::PageTableFlags;
+    use yzos::address_space::{
+        active_l4_frame, frame_ptr, leaf_entry, map_user_page, USER_SPACE_START,
+    };
+
+    let process = Process::new();
+    let addr = VirtAddr::new(USER_SPACE_START);
+    let page = Page::containing_address(addr);
+    map_user_page(process.get_page_table(), page, PageTableFlags::WRITABLE)
+        .expect("map_user_page failed");
+
+    let kernel_l4 = active_l4_frame();
+    let ptr: *mut u64 = addr.as_mut_ptr();
+    process.activate_address_space();
+    // the TLB now holds a writable entry for `addr`
+    unsafe { ptr.write_volatile(1) };
+    unsafe { Cr3::write(kernel_l4, Cr3Flags::empty()) };
+
+    // the page becomes copy-on-write while the process is not running
+    let child = process.fork_address_space().expect("fork failed");
+    process.activate_address_space();
+    // this write must fault instead of going through the old entry
+    unsafe { ptr.write_volatile(2) };
+    unsafe { Cr3::write(kernel_l4, Cr3Flags::empty()) };
+
+    let child_frame = leaf_entry(child.get_page_table(), addr).unwrap().frame().unwrap();
+    assert_eq!(unsafe { (frame_ptr(child_frame) as *const u64).read_volatile() }, 1);
+    println!(
+        "PCID switches keep the TLB coherent (PCID enabled: {})",
+        yzos::tlb::pcid_enabled()
+    );
+}
+
+// NOTE: two processes map the same shared memory object at different addresses,
+// the frames go away with the last mapping and handle
+#[allow(dead_code)]
+fn test_shared_memory() {
+    use x86_64::registers::control::{Cr3, Cr3Flags};
+    use x86_64::structures::paging::PageTableFlags;
+    use yzos::address_space::{active_l4_frame, leaf_entry, USER_SPACE_START};
+    use yzos::frame_allocator::get_frame_allocator;
+    use yzos::shared_memory::SharedMemory;
+    use yzos::vma::VmaFlags;
+
+    let free_frames = get_frame_allocator().free_frame_num();
+    let writer_addr = VirtAddr::new(USER_SPACE_START);
+    let reader_addr = VirtAddr::new(USER_SPACE_START + 0x10_0000);
+    {
+        let shm = SharedMemory::new(2).expect("SharedMemory::new failed");
+        let mut writer = Process::new();
+        let mut reader = Process::new();
+        writer
+            .map_shared(&shm, writer_addr.as_u64(), VmaFlags::READ | VmaFlags::WRITE)
+            .expect("map_shared failed");
+        let reader_shm = SharedMemory::open(shm.get_id()).expect("SharedMemory::open failed");
+        reader
+            .map_shared(&reader_shm, reader_addr.as_u64(), VmaFlags::READ)
+            .expect("map_shared failed");
+        // the object lives on in the mappings
+        drop(shm);
+        drop(reader_shm);
+
+        let entry = leaf_entry(reader.get_page_table(), reader_addr + 4096u64).unwrap();
+        assert!(!entry.flags().contains(PageTableFlags::WRITABLE));
+
+        let kernel_l4 = active_l4_frame();
+        unsafe {
+            writer.activate_address_space();
+            (writer_addr + 4096u64).as_mut_ptr::<u64>().write_volatile(42);
+            reader.activate_address_space();
+            assert_eq!((reader_addr + 4096u64).as_ptr::<u64>().read_volatile(), 42);
+            Cr3::write(kernel_l4, Cr3Flags::empty());
+        }
+
+        // a forked writer shares the pages instead of copying them
+        let child = writer.fork_address_space().expect("fork failed");
+        let child_entry = leaf_entry(child.get_page_table(), writer_addr).unwrap();
+        assert!(child_entry.flags().contains(PageTableFlags::WRITABLE));
+        writer.unmap(writer_addr.as_u64(), writer_addr.as_u64() + 2 * 4096).unwrap();
+    }
+    // more frames than one index frame lists
+    let large = SharedMemory::new(600).expect("SharedMemory::new failed");
+    assert!(large.frame(599).is_some() && large.frame(600).is_none());
+    drop(large);
+    assert_eq!(get_frame_allocator().free_frame_num(), free_frames);
+    println!("shared memory works");
+}
+
+// NOTE: swap out the pages of a process, then touch them again
+#[allow(dead_code)]
+fn test_swap() {
+    use x86_64::registers::control::{Cr3, Cr3Flags};
+    use x86_64::structures::paging::PageTableFlags;
+    use yzos::address_space::{active_l4_frame, map_user_page, USER_SPACE_START};
+    use yzos::frame_allocator::get_frame_allocator;
+    use yzos::swap::{is_swapped_out, reclaim, swap_out, used_slots};
+
+    let free_frames = get_frame_allocator().free_frame_num();
+    let used = used_slots();
+    let page_addr = |i: u64| VirtAddr::new(USER_SPACE_START + i * 4096);
+    {
+        let process = Process::new();
+        let l4_frame = process.get_page_table();
+        for i in 0..8 {
+            let page = Page::containing_address(page_addr(i));
+            map_user_page(l4_frame, page, PageTableFlags::WRITABLE).expect("map_user_page failed");
+        }
+        let kernel_l4 = active_l4_frame();
+        process.activate_address_space();
+        for i in 0..8 {
+            unsafe { page_addr(i).as_mut_ptr::<u64>().write_volatile(i + 1) };
+        }
+        unsafe { Cr3::write(kernel_l4, Cr3Flags::empty()) };
+
+        for i in 0..8 {
+            assert!(swap_out(l4_frame, page_addr(i)));
+            assert!(is_swapped_out(l4_frame, page_addr(i)));
+        }
+        assert_eq!(used_slots(), used + 8);
+
+        // every access faults and reads the page back
+        process.activate_address_space();
+        for i in 0..8 {
+            assert_eq!(unsafe { page_addr(i).as_ptr::<u64>().read_volatile() }, i + 1);
+        }
+        unsafe { Cr3::write(kernel_l4, Cr3Flags::empty()) };
+        assert_eq!(used_slots(), used);
+
+        // the pages were just used, so they are only taken on the second pass
+        assert_eq!(reclaim(8), 8);
+        assert!(is_swapped_out(l4_frame, page_addr(7)));
+    }
+    assert_eq!(used_slots(), used);
+    assert_eq!(get_frame_allocator().free_frame_num(), free_frames);
+    println!("swapping works");
+}
+
+// NOTE: touch more pages than there are free frames, the rest has to go to swap
+// run QEMU with a small `-m` to keep it fast
+#[allow(dead_code)]
+fn test_overcommit() {
+    use x86_64::registers::control::{Cr3, Cr3Flags};
+    use x86_64::structures::paging::PageTableFlags;
+    use yzos::address_space::{active_l4_frame, map_user_page, USER_SPACE_START};
+    use yzos::frame_allocator::get_frame_allocator;
+
+    let page_num = get_frame_allocator().free_frame_num() as u64 + 256;
+    let page_addr = |i: u64| VirtAddr::new(USER_SPACE_START + i * 4096);
+    let process = Process::new();
+    let kernel_l4 = active_l4_frame();
+    process.activate_address_space();
+    for i in 0..page_num {
+        let page = Page::containing_address(page_addr(i));
+        map_user_page(process.get_page_table(), page, PageTableFlags::WRITABLE)
+            .expect("out of memory and swap");
+        unsafe { page_addr(i).as_mut_ptr::<u64>().write_volatile(i) };
+    }
+    for i in 0..page_num {
+        assert_eq!(unsafe { page_addr(i).as_ptr::<u64>().read_volatile() }, i);
+    }
+    unsafe { Cr3::write(kernel_l4, Cr3Flags::empty()) };
+    println!("{} pages overcommitted", page_num);
+}
+
+// NOTE: vmalloc buffers are virtually contiguous and separated by unmapped guard pages
+#[allow(dead_code)]
+fn test_vmalloc() {
+    use yzos::address_space::{active_l4_frame, leaf_entry};
+    use yzos::frame_allocator::get_frame_allocator;
+    use yzos::vmalloc::{vfree, vmalloc, vmalloc_size};
+
+    // the first allocation creates the page tables of the vmalloc area, which stay
+    vfree(vmalloc(4096).expect("vmalloc failed"));
+    let free_frames = get_frame_allocator().free_frame_num();
+
+    let sizes = [100usize, 64 * 1024, 3 * 4096 + 1];
+    let mut buffers = [VirtAddr::new(0); 3];
+    for (i, &size) in sizes.iter().enumerate() {
+        let buffer = vmalloc(size).expect("vmalloc failed");
+        assert_eq!(vmalloc_size(buffer), Some((size + 4095) / 4096 * 4096));
+        let bytes = unsafe { core::slice::from_raw_parts_mut(buffer.as_mut_ptr::<u8>(), size) };
+        assert!(bytes.iter().all(|&byte| byte == 0));
+        for byte in bytes.iter_mut() {
+            *byte = i as u8 + 1;
+        }
+        // nothing is mapped right after the buffer
+        let guard = buffer + vmalloc_size(buffer).unwrap() as u64;
+        assert!(leaf_entry(active_l4_frame(), guard).map_or(true, |entry| entry.is_unused()));
+        buffers[i] = buffer;
+    }
+    for (i, &buffer) in buffers.iter().enumerate() {
+        let bytes = unsafe { core::slice::from_raw_parts(buffer.as_ptr::<u8>(), sizes[i]) };
+        assert!(bytes.iter().all(|&byte| byte == i as u8 + 1));
+        vfree(buffer);
+    }
+    assert_eq!(get_frame_allocator().free_frame_num(), free_frames);
+    println!("vmalloc works");
+}
+
+// NOTE: share the timer line with a second handler, then take it away again
+static mut SHARED_TIMER_TICKS: usize = 0;
+
+fn count_shared_timer_tick(_irq: u8) {
+    unsafe { SHARED_TIMER_TICKS += 1 };
+}
+
+#[allow(dead_code)]
+fn test_irq_handlers() {
+    use yzos::irq::{register_irq_handler, unregister_irq_handler, IrqError, TIMER_IRQ};
+
+    let id = register_irq_handler(TIMER_IRQ, count_shared_timer_tick).expect("register failed");
+    // every timer interrupt wakes us up, the timer itself still ticks
+    while unsafe { core::ptr::read_volatile(&SHARED_TIMER_TICKS) } < 3 {
+        x86_64::instructions::hlt();
+    }
+    unregister_irq_handler(id).expect("unregister failed");
+    assert_eq!(unregister_irq_handler(id), Err(IrqError::NotRegistered));
+
+    let ticks = unsafe { core::ptr::read_volatile(&SHARED_TIMER_TICKS) };
+    for _ in 0..3 {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(unsafe { core::ptr::read_volatile(&SHARED_TIMER_TICKS) }, ticks);
+    assert_eq!(register_irq_handler(16, count_shared_timer_tick), Err(IrqError::InvalidIrq));
+    println!("irq handler registration works");
+}
+
+// NOTE: sleep and busy wait, the clock has to advance by at least the requested time
+#[allow(dead_code)]
+fn test_time() {
+    use core::time::Duration;
+    use yzos::time::{busy_wait, sleep, ticks, uptime, Instant};
+
+    let start = Instant::now();
+    let start_ticks = ticks();
+    sleep(Duration::from_millis(50));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert!(ticks() - start_ticks >= 50);
+
+    let start = Instant::now();
+    busy_wait(Duration::from_millis(10));
+    assert!(start.elapsed() >= Duration::from_millis(10));
+    println!("time works, uptime {:?}", uptime());
+}
+
+// NOTE: the TSC has to agree with the PIT ticks, and be fine enough to time a frame allocation
+#[allow(dead_code)]
+fn test_tsc() {
+    use core::time::Duration;
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+    use yzos::frame_allocator::get_frame_allocator;
+    use yzos::time::sleep;
+    use yzos::tsc::now;
+
+    let start = now();
+    sleep(Duration::from_millis(100));
+    let elapsed = now() - start;
+    // one tick of slack below, the sleep may take much longer on an emulated or busy CPU.
+    // the upper bound only catches a calibration that is way off
+    assert!(elapsed >= 98_000_000 && elapsed < 1_000_000_000);
+
+    let frame_allocator = get_frame_allocator();
+    let start = now();
+    let frame = frame_allocator.allocate_frame().expect("out of memory");
+    let allocation = now() - start;
+    frame_allocator.deallocate_frame(frame);
+    println!("tsc works, a frame allocation took {}ns", allocation);
+}
+
+// NOTE: one-shot, periodic, cancelled and re-armed timers
+#[allow(dead_code)]
+fn test_timers() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::time::Duration;
+    use yzos::time::sleep;
+    use yzos::timer::{cancel_timer, rearm_timer, set_periodic_timer, set_timer};
+
+    static ONE_SHOT: AtomicUsize = AtomicUsize::new(0);
+    static PERIODIC: AtomicUsize = AtomicUsize::new(0);
+    static CANCELLED: AtomicUsize = AtomicUsize::new(0);
+
+    set_timer(Duration::from_millis(20), || {
+        ONE_SHOT.fetch_add(1, Ordering::Relaxed);
+    });
+    let periodic = set_periodic_timer(Duration::from_millis(10), || {
+        PERIODIC.fetch_add(1, Ordering::Relaxed);
+    });
+    let cancelled = set_timer(Duration::from_millis(30), || {
+        CANCELLED.fetch_add(1, Ordering::Relaxed);
+    });
+    let watchdog = set_timer(Duration::from_millis(30), || {
+        panic!("the watchdog was pushed out, it must not fire");
+    });
+    assert!(cancel_timer(cancelled));
+    assert!(rearm_timer(watchdog, Duration::from_secs(60)));
+
+    sleep(Duration::from_millis(105));
+    assert!(cancel_timer(periodic));
+    assert!(cancel_timer(watchdog));
+    assert_eq!(ONE_SHOT.load(Ordering::Relaxed), 1);
+    assert_eq!(CANCELLED.load(Ordering::Relaxed), 0);
+    let periods = PERIODIC.load(Ordering::Relaxed);
+    assert!(periods >= 9 && periods <= 11);
+    println!("timers work");
+}
+
+// NOTE: work deferred by an interrupt handler runs after the handler with interrupts enabled
+static mut DEFERRED_RUNS: usize = 0;
+static mut DEFERRED_WITH_INTERRUPTS: bool = true;
+
+fn deferred_timer_work(_data: usize) {
+    unsafe {
+        DEFERRED_RUNS += 1;
+        DEFERRED_WITH_INTERRUPTS &= x86_64::instructions::interrupts::are_enabled();
+    }
+}
+
+fn defer_from_timer(_irq: u8) {
+    yzos::softirq::queue_work(deferred_timer_work, 0);
+}
+
+#[allow(dead_code)]
+fn test_deferred_work() {
+    use yzos::irq::{irq_stats, print_irq_stats, register_irq_handler, unregister_irq_handler};
+    use yzos::irq::TIMER_IRQ;
+
+    let deferred_before = irq_stats(TIMER_IRQ).unwrap().deferred;
+    let id = register_irq_handler(TIMER_IRQ, defer_from_timer).expect("register failed");
+    while unsafe { core::ptr::read_volatile(&DEFERRED_RUNS) } < 5 {
+        x86_64::instructions::hlt();
+    }
+    unregister_irq_handler(id).expect("unregister failed");
+    assert!(unsafe { DEFERRED_WITH_INTERRUPTS });
+    assert!(irq_stats(TIMER_IRQ).unwrap().deferred >= deferred_before + 5);
+    print_irq_stats();
+    println!("deferred work works");
+}
+
+// NOTE: timer ticks and a breakpoint have to show up in the vector statistics
+#[allow(dead_code)]
+fn test_interrupt_stats() {
+    use core::time::Duration;
+    use yzos::exceptions::BREAKPOINT;
+    use yzos::interrupt_stats::{print_interrupt_stats, vector_stats};
+    use yzos::irq::{vector_of, TIMER_IRQ};
+    use yzos::time::sleep;
+
+    let timer = vector_stats(vector_of(TIMER_IRQ));
+    let breakpoint = vector_stats(BREAKPOINT);
+    sleep(Duration::from_millis(20));
+    x86_64::instructions::interrupts::int3();
+
+    let timer_after = vector_stats(vector_of(TIMER_IRQ));
+    assert!(timer_after.count >= timer.count + 20);
+    assert!(timer_after.cycles > timer.cycles);
+    assert_eq!(vector_stats(BREAKPOINT).count, breakpoint.count + 1);
+    print_interrupt_stats();
+    println!("interrupt statistics work");
+}
+
 use yzos::data_structures::{LinkedList, LinkedListNode};
 #[allow(dead_code)]
 fn test_linked_list() {
@@ -212,6 +886,33 @@ fn stack_overflow() {
     stack_overflow();
 }
 
+// NOTE: overflow a process kernel stack, reported with the owner's pid
+// the guard page is unmapped so this never touches a neighboring stack
+#[allow(dead_code)]
+fn trigger_kernel_stack_overflow() {
+    let process = Process::new();
+    let stack = process.context.get_stack().unwrap();
+    let below_bottom = (stack.bottom().as_u64() - 8) as *mut u64;
+    unsafe { below_bottom.write_volatile(42) };
+}
+
+// NOTE: each of these is reported by its own exception handler
+#[allow(dead_code)]
+fn trigger_invalid_opcode() {
+    unsafe { asm!("ud2" : : : : "volatile") };
+}
+
+#[allow(dead_code)]
+fn trigger_divide_error() {
+    unsafe { asm!("xor ecx, ecx; div ecx" : : : "eax", "ecx", "edx" : "intel", "volatile") };
+}
+
+// loading a selector beyond the end of the GDT, the error code names the selector
+#[allow(dead_code)]
+fn trigger_general_protection_fault() {
+    unsafe { asm!("mov ax, 0x80; mov ds, ax" : : : "eax" : "intel", "volatile") };
+}
+
 #[allow(dead_code)]
 fn trigger_double_fault() {
     stack_overflow();
@@ -247,28 +948,27 @@ fn access_l4_page_table() {
     );
 }
 
-// NOTE: iterate over active level 4 and 3 page tables
-use yzos::memory::active_level_4_table;
+// NOTE: dump the mappings of the active page table, coalesced into ranges
 #[allow(dead_code)]
-fn display_l4_l3_page_table(boot_info: &'static BootInfo) {
-    let l4_table = unsafe { active_level_4_table(boot_info.physical_memory_offset) };
-    use x86_64::structures::paging::PageTable;
-    for (i, entry) in l4_table.iter().enumerate() {
-        if !entry.is_unused() {
-            println!("L4 Entry {}: {:?}", i, entry);
+fn display_page_table() {
+    use yzos::address_space::active_l4_frame;
+    use yzos::page_walker::{dump, DumpFilter};
 
-            let phys = entry.frame().unwrap().start_address();
-            let virt = phys.as_u64() + boot_info.physical_memory_offset;
-            let ptr = VirtAddr::new(virt).as_mut_ptr();
-            let l3_table: &PageTable = unsafe { &*ptr };
+    dump(active_l4_frame(), DumpFilter::empty());
+}
 
-            for (i, entry) in l3_table.iter().enumerate() {
-                if !entry.is_unused() {
-                    println!("L3 Entry {}: {:?}", i, entry);
-                }
-            }
-        }
-    }
+// NOTE: audit a freshly created process, it must not have any user mapping yet
+// and nothing may be writable and executable at the same time
+#[allow(dead_code)]
+fn audit_process_page_table() {
+    use yzos::page_walker::{dump, walk_ranges, DumpFilter};
+
+    let process = Process::new();
+    let l4_frame = process.get_page_table();
+    let mut user_ranges = 0;
+    walk_ranges(l4_frame, DumpFilter::USER_ONLY, &mut |_| user_ranges += 1);
+    assert_eq!(user_ranges, 0);
+    dump(l4_frame, DumpFilter::WRITABLE_EXECUTABLE);
 }
 
 //NOTE: for testing translating virtual address into physical address
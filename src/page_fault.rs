@@ -0,0 +1,182 @@
+use crate::address_space::{active_l4_frame, is_kernel_l4_slot, map_user_page, resolve_cow};
+use crate::frame_allocator::get_frame_allocator;
+use crate::memory;
+use crate::process::ACTIVE_PROCESS;
+use crate::swap::{is_swapped_out, swap_in};
+use crate::vma::{Vma, VmaBacking, VmaError, VmaFlags, VmaSet};
+use crate::PHYSICAL_MEMORY_OFFSET;
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{Mapper, Page, PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultError {
+    // the address is not inside any VMA
+    NotInRegion,
+    // the address is inside a stack guard area
+    StackGuard,
+    // the page is present, so this is a protection violation
+    ProtectionViolation,
+    // writing to a read-only area
+    WriteToReadOnly,
+    // instruction fetch from a non-executable area
+    ExecuteNotAllowed,
+    // user mode access to a kernel area
+    UserAccessToKernel,
+    // the backing type cannot be faulted in (yet)
+    Unsupported,
+    OutOfMemory,
+    MapFailed,
+}
+
+// NOTE: areas of the kernel half, e.g. lazily-backed kernel heaps
+// user areas live in the `VmaSet` of each process
+// the set is only locked with interrupts disabled, the page fault handler looks into it
+lazy_static! {
+    static ref KERNEL_VMAS: Mutex<VmaSet> = Mutex::new(VmaSet::new());
+}
+
+// reserve [start, end) in the kernel address space
+// frames are allocated (and zeroed) the first time a page is touched
+// `start` and `end` are rounded to page boundaries
+pub fn register_lazy_region(
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: VmaFlags,
+) -> Result<(), VmaError> {
+    let start_page: Page<Size4KiB> = Page::containing_address(start);
+    let end_page: Page<Size4KiB> = Page::containing_address(end - 1u64);
+    let vma = Vma::new(
+        start_page.start_address().as_u64(),
+        end_page.start_address().as_u64() + Size4KiB::SIZE,
+        flags - VmaFlags::USER,
+        VmaBacking::Anonymous,
+    );
+    register_kernel_vma(vma)
+}
+
+pub fn unregister_lazy_region(start: VirtAddr, end: VirtAddr) -> Result<(), VmaError> {
+    unregister_kernel_vma(start, end)
+}
+
+pub fn register_kernel_vma(vma: Vma) -> Result<(), VmaError> {
+    without_interrupts(|| KERNEL_VMAS.lock().insert(vma))
+}
+
+pub fn unregister_kernel_vma(start: VirtAddr, end: VirtAddr) -> Result<(), VmaError> {
+    without_interrupts(|| KERNEL_VMAS.lock().remove(start.as_u64(), end.as_u64()))?;
+    Ok(())
+}
+
+pub fn vma_to_page_table_flags(flags: VmaFlags) -> PageTableFlags {
+    let mut page_table_flags = PageTableFlags::PRESENT;
+    if flags.contains(VmaFlags::WRITE) {
+        page_table_flags |= PageTableFlags::WRITABLE;
+    }
+    if !flags.contains(VmaFlags::EXEC) {
+        page_table_flags |= PageTableFlags::NO_EXECUTE;
+    }
+    if flags.contains(VmaFlags::USER) {
+        page_table_flags |= PageTableFlags::USER_ACCESSIBLE;
+    }
+    page_table_flags
+}
+
+// the VMA covering `addr`, either from the kernel set or from the active process
+fn find_vma(addr: VirtAddr) -> Option<Vma> {
+    if is_kernel_l4_slot(usize::from(addr.p4_index())) {
+        return KERNEL_VMAS.lock().find(addr.as_u64()).cloned();
+    }
+    if unsafe { ACTIVE_PROCESS.is_null() } {
+        return None;
+    }
+    let process = unsafe { &*ACTIVE_PROCESS };
+    process.vmas.find(addr.as_u64()).cloned()
+}
+
+// called by `page_fault_handler`
+// returns Ok(()) if the fault is resolved and the faulting instruction can be restarted
+pub fn handle_page_fault(
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> Result<(), PageFaultError> {
+    // write to a present copy-on-write page
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && resolve_cow(addr)
+    {
+        return Ok(());
+    }
+
+    // a swapped out page was mapped before, the VMA checks are done once it is back
+    let l4_frame = active_l4_frame();
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && is_swapped_out(l4_frame, addr)
+    {
+        if swap_in(l4_frame, addr) {
+            return Ok(());
+        }
+        return Err(PageFaultError::OutOfMemory);
+    }
+
+    let vma = find_vma(addr).ok_or(PageFaultError::NotInRegion)?;
+
+    if vma.backing == VmaBacking::StackGuard {
+        return Err(PageFaultError::StackGuard);
+    }
+    if error_code.contains(PageFaultErrorCode::USER_MODE) && !vma.flags.contains(VmaFlags::USER) {
+        return Err(PageFaultError::UserAccessToKernel);
+    }
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !vma.flags.contains(VmaFlags::WRITE)
+    {
+        return Err(PageFaultError::WriteToReadOnly);
+    }
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+        && !vma.flags.contains(VmaFlags::EXEC)
+    {
+        return Err(PageFaultError::ExecuteNotAllowed);
+    }
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return Err(PageFaultError::ProtectionViolation);
+    }
+
+    let page: Page<Size4KiB> = Page::containing_address(addr);
+    let flags = vma_to_page_table_flags(vma.flags);
+    match vma.backing {
+        VmaBacking::Anonymous if vma.flags.contains(VmaFlags::USER) => {
+            map_user_page(l4_frame, page, flags).ok_or(PageFaultError::MapFailed)?;
+            Ok(())
+        }
+        VmaBacking::Anonymous => map_zeroed_page(page, flags),
+        _ => Err(PageFaultError::Unsupported),
+    }
+}
+
+fn map_zeroed_page(page: Page<Size4KiB>, flags: PageTableFlags) -> Result<(), PageFaultError> {
+    let frame_allocator = get_frame_allocator();
+    let frame = frame_allocator
+        .alloc_zeroed_frame()
+        .ok_or(PageFaultError::OutOfMemory)?;
+
+    let mut mapper = unsafe { memory::init(PHYSICAL_MEMORY_OFFSET as u64) };
+    let map_to_result = unsafe { mapper.map_to(page, frame, flags, frame_allocator) };
+    match map_to_result {
+        Ok(flush) => {
+            flush.flush();
+            if let Some(frame_info) = frame_allocator.frame_info(frame) {
+                frame_info.inc_count();
+            }
+            Ok(())
+        }
+        Err(_) => {
+            use x86_64::structures::paging::FrameDeallocator;
+            frame_allocator.deallocate_frame(frame);
+            Err(PageFaultError::MapFailed)
+        }
+    }
+}
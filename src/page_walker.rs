@@ -0,0 +1,210 @@
+use crate::address_space::{canonical, table_of, COW, SHARED};
+use crate::println;
+
+use bitflags::bitflags;
+use core::fmt;
+use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+
+// NOTE: walks all four levels of any page table, not only the active one
+// 1GiB (L3) and 2MiB (L2) huge pages are reported as single mappings
+
+bitflags! {
+    pub struct DumpFilter: u32 {
+        // only mappings reachable from user mode
+        const USER_ONLY = 0x1;
+        // only mappings that are both writable and executable
+        const WRITABLE_EXECUTABLE = 0x2;
+    }
+}
+
+// the permission bits that matter when coalescing mappings
+// ACCESSED and DIRTY change all the time and are ignored
+fn permission_bits(flags: PageTableFlags) -> PageTableFlags {
+    flags
+        & (PageTableFlags::WRITABLE
+            | PageTableFlags::USER_ACCESSIBLE
+            | PageTableFlags::NO_EXECUTE
+            | PageTableFlags::GLOBAL
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH
+            | COW
+            | SHARED)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub virt: u64,
+    pub phys: u64,
+    pub size: u64,
+    // effective flags: WRITABLE and USER_ACCESSIBLE only if set on every level,
+    // NO_EXECUTE if set on any level
+    pub flags: PageTableFlags,
+}
+
+// a run of mappings contiguous in both virtual and physical memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappingRange {
+    pub virt_start: u64,
+    pub virt_end: u64,
+    pub phys_start: u64,
+    pub flags: PageTableFlags,
+}
+
+impl MappingRange {
+    pub fn phys_end(&self) -> u64 {
+        self.phys_start + (self.virt_end - self.virt_start)
+    }
+
+    fn extend(&mut self, mapping: &Mapping) -> bool {
+        if self.virt_end == mapping.virt
+            && self.phys_end() == mapping.phys
+            && self.flags == permission_bits(mapping.flags)
+        {
+            self.virt_end += mapping.size;
+            return true;
+        }
+        false
+    }
+}
+
+impl fmt::Display for MappingRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#x}-{:#x}, {}",
+            self.virt_start,
+            self.virt_end,
+            self.phys_start,
+            self.phys_end(),
+            FlagsDisplay(self.flags)
+        )
+    }
+}
+
+// compact "rwxug" style flags
+pub struct FlagsDisplay(pub PageTableFlags);
+
+impl fmt::Display for FlagsDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = self.0;
+        let bit = |flag: PageTableFlags, c: char| if flags.contains(flag) { c } else { '-' };
+        write!(
+            f,
+            "r{}{}{}{}",
+            bit(PageTableFlags::WRITABLE, 'w'),
+            if flags.contains(PageTableFlags::NO_EXECUTE) { '-' } else { 'x' },
+            bit(PageTableFlags::USER_ACCESSIBLE, 'u'),
+            bit(PageTableFlags::GLOBAL, 'g'),
+        )?;
+        if flags.contains(COW) {
+            write!(f, " cow")?;
+        }
+        if flags.contains(SHARED) {
+            write!(f, " shared")?;
+        }
+        Ok(())
+    }
+}
+
+fn combine(parent: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut flags = entry;
+    flags.remove(inherited - (parent & inherited));
+    if parent.contains(PageTableFlags::NO_EXECUTE) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+// call `visit` for every present leaf mapping in ascending virtual address order
+pub fn walk<F>(l4_frame: PhysFrame, visit: &mut F)
+where
+    F: FnMut(Mapping),
+{
+    let root_flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let l4 = unsafe { table_of(l4_frame) };
+    walk_table(l4, 4, 0, root_flags, visit);
+}
+
+fn walk_table<F>(
+    table: &PageTable,
+    level: u32,
+    base: u64,
+    parent_flags: PageTableFlags,
+    visit: &mut F,
+) where
+    F: FnMut(Mapping),
+{
+    // each entry of a level `n` table covers 4KiB << (9 * (n - 1))
+    let entry_size: u64 = 4096 << (9 * (level - 1));
+    for (i, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let virt = base + i as u64 * entry_size;
+        let effective = combine(parent_flags, flags);
+
+        let is_leaf = level == 1 || (level < 4 && flags.contains(PageTableFlags::HUGE_PAGE));
+        if is_leaf {
+            visit(Mapping {
+                virt: canonical(virt),
+                phys: entry.addr().as_u64(),
+                size: entry_size,
+                flags: effective,
+            });
+        } else if let Ok(frame) = entry.frame() {
+            let next = unsafe { table_of(frame) };
+            walk_table(next, level - 1, virt, effective, visit);
+        }
+    }
+}
+
+fn accept(filter: DumpFilter, flags: PageTableFlags) -> bool {
+    if filter.contains(DumpFilter::USER_ONLY) && !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        return false;
+    }
+    if filter.contains(DumpFilter::WRITABLE_EXECUTABLE)
+        && !(flags.contains(PageTableFlags::WRITABLE)
+            && !flags.contains(PageTableFlags::NO_EXECUTE))
+    {
+        return false;
+    }
+    true
+}
+
+// call `visit` for all mappings passing `filter`, coalesced into contiguous ranges
+// nothing is collected on the heap, a whole kernel page table has far too many ranges
+pub fn walk_ranges<F>(l4_frame: PhysFrame, filter: DumpFilter, visit: &mut F)
+where
+    F: FnMut(MappingRange),
+{
+    let mut pending: Option<MappingRange> = None;
+    walk(l4_frame, &mut |mapping: Mapping| {
+        if !accept(filter, mapping.flags) {
+            return;
+        }
+        if let Some(range) = pending.as_mut() {
+            if range.extend(&mapping) {
+                return;
+            }
+            visit(*range);
+        }
+        pending = Some(MappingRange {
+            virt_start: mapping.virt,
+            virt_end: mapping.virt + mapping.size,
+            phys_start: mapping.phys,
+            flags: permission_bits(mapping.flags),
+        });
+    });
+    if let Some(range) = pending {
+        visit(range);
+    }
+}
+
+// print one line per range, e.g.
+// 0x0000000000200000-0x0000000000208000 -> 0x400000-0x408000, r-x--
+pub fn dump(l4_frame: PhysFrame, filter: DumpFilter) {
+    println!("page table at {:?}:", l4_frame.start_address());
+    walk_ranges(l4_frame, filter, &mut |range| println!("{}", range));
+}
@@ -1,105 +1,233 @@
 use crate::context::Context;
+use crate::kernel_stack::KernelStack;
 use crate::println;
+use crate::sched_policy::SchedInfo;
+use crate::shared_memory::{SharedMemory, ShmError};
+use crate::tlb;
+use crate::vma::{Vma, VmaBacking, VmaError, VmaFlags, VmaSet};
 
 use alloc::boxed::Box;
 use alloc::vec::Vec;
-// use lazy_static::lazy_static;
-// use spin::Mutex;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PhysFrame;
 
-// the tid of kernel thread is 0;
-pub static mut NEXT_PID: usize = 0;
 pub static mut ACTIVE_PROCESS: *mut Process = core::ptr::null_mut();
 
+// the tid of kernel thread is 0;
+// the pid of an exited process is handed out again once its parent collected the exit code
+// (see `scheduler::wait`), or once the parent exited without doing so. Nobody collects the
+// exit code of a detached process, its pid is freed once it is reaped
+const MAX_PIDS: usize = 8192;
+
+lazy_static! {
+    // a bit per pid, set while the pid is in use
+    static ref PIDS: Mutex<[u64; MAX_PIDS / 64]> = Mutex::new({
+        let mut pids = [0; MAX_PIDS / 64];
+        pids[0] = 1;
+        pids
+    });
+}
+
+fn alloc_pid() -> Option<usize> {
+    interrupts::without_interrupts(|| {
+        let mut pids = PIDS.lock();
+        let word = pids.iter().position(|&word| word != u64::max_value())?;
+        let bit = (!pids[word]).trailing_zeros() as usize;
+        pids[word] |= 1 << bit;
+        Some(word * 64 + bit)
+    })
+}
+
+pub(crate) fn free_pid(pid: usize) {
+    interrupts::without_interrupts(|| PIDS.lock()[pid / 64] &= !(1 << (pid % 64)));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    // waiting in the ready queue
+    Ready,
+    // the active process
+    Running,
+    // waiting for `scheduler::wake`
+    Blocked,
+    // done, never runs again and waits for the reaper
+    Zombie,
+}
+
+// called with the exit code once the process exited, interrupts are disabled
+pub type ExitHook = Box<dyn FnOnce(i32) + Send>;
+
 pub struct Process {
     // init: bool,
     pub pid: usize,
+    pub state: ProcessState,
+    // priority, nice and whatever the scheduling policy keeps track of
+    sched: SchedInfo,
     pub context: Context,
+    // valid ranges of the user half, consulted by the page fault handler
+    pub vmas: VmaSet,
+    // TLB tag of the address space, None if PCIDs are not available
+    pcid: Option<u16>,
+    // the process that created this one
+    parent: usize,
+    exit_code: Option<i32>,
+    exit_hook: Option<ExitHook>,
+    // the exit code is not handed to the parent, e.g. for orphans and unjoined threads
+    detached: bool,
+    // pids and exit codes of children that exited, see `scheduler::wait`
+    pub exited_children: Vec<(usize, i32)>,
 }
 
 impl Process {
-    pub fn new(mut stack: Vec<u8>) -> Self {
-        let stack_ptr = stack.as_mut_ptr();
-        let rsp = unsafe { stack_ptr.offset(stack.len() as isize) as usize };
+    pub fn new() -> Self {
+        let cr3 = Process::init_page_table();
+        Process::with_page_table(cr3)
+    }
 
-        // FIXME: data race here?
-        unsafe { NEXT_PID += 1 };
-        let pid = unsafe { NEXT_PID };
+    // the process the kernel was running in since boot, on the boot stack and page table
+    pub fn kernel() -> Self {
+        Process {
+            pid: 0,
+            state: ProcessState::Running,
+            sched: SchedInfo::default(),
+            context: Context::current(),
+            vmas: VmaSet::new(),
+            pcid: None,
+            parent: 0,
+            exit_code: None,
+            exit_hook: None,
+            detached: false,
+            exited_children: Vec::new(),
+        }
+    }
 
-        let cr3 = Process::init_page_table();
-        let context = Context::new(cr3, rsp, stack);
+    // NOTE: the new process shares all user frames with `self` copy-on-write
+    // this is the basis for `fork`
+    pub fn fork_address_space(&self) -> Option<Self> {
+        use crate::address_space::clone_address_space;
+
+        let cr3 = clone_address_space(self.get_page_table())?;
+        let mut process = Process::with_page_table(cr3.start_address().as_u64() as usize);
+        process.vmas = self.vmas.clone();
+        Some(process)
+    }
+
+    fn with_page_table(cr3: usize) -> Self {
+        let pid = alloc_pid().expect("out of pids");
+        let parent = match unsafe { ACTIVE_PROCESS.as_ref() } {
+            Some(process) => process.get_pid(),
+            None => 0,
+        };
+
+        let stack = KernelStack::new(pid).expect("out of memory while allocating a kernel stack");
+        let context = Context::new(cr3, stack);
+        let pcid = tlb::alloc_pcid(tlb::cr3_frame(cr3));
+        crate::swap::register_address_space(tlb::cr3_frame(cr3));
         Process {
             // init: false,
             pid: pid,
+            state: ProcessState::Ready,
+            sched: SchedInfo::default(),
             context: context,
+            vmas: VmaSet::new(),
+            pcid: pcid,
+            parent: parent,
+            exit_code: None,
+            exit_hook: None,
+            detached: false,
+            exited_children: Vec::new(),
         }
     }
 
-    // TODO:
-    // The process are created from the kernel process
-    // therefore when we construct the new page table
-    // we should reserve the content in the kernel page
-    fn init_page_table() -> usize {
-        use crate::memory::virt2phys;
-        use crate::PHYSICAL_MEMORY_OFFSET;
+    // reserve [start, end) of the user half, pages are faulted in on first touch
+    pub fn map_anonymous(
+        &mut self,
+        start: u64,
+        end: u64,
+        flags: VmaFlags,
+    ) -> Result<(), VmaError> {
+        use crate::address_space::{USER_SPACE_END, USER_SPACE_START};
 
-        use x86_64::registers::control::Cr3;
-        use x86_64::structures::paging::PageTable;
-        use x86_64::VirtAddr;
+        if start < USER_SPACE_START || end > USER_SPACE_END {
+            return Err(VmaError::NotMapped);
+        }
+        let vma = Vma::new(start, end, flags | VmaFlags::USER, VmaBacking::Anonymous);
+        self.vmas.insert(vma)
+    }
 
-        // read kernel CR3
-        // since we should always create a process from kernel space
-        // this should be fine?
-        let (level_4_table_frame, _) = Cr3::read();
-        let cr3_phys = level_4_table_frame.start_address();
-        let cr3_virt = unsafe { VirtAddr::new(cr3_phys.as_u64() + PHYSICAL_MEMORY_OFFSET as u64) };
+    // map the whole shared memory object at `start` with `flags`
+    // the pages are mapped right away, there is nothing to fault in
+    pub fn map_shared(
+        &mut self,
+        shm: &SharedMemory,
+        start: u64,
+        flags: VmaFlags,
+    ) -> Result<(), ShmError> {
+        use crate::address_space::{map_user_frame, SHARED, USER_SPACE_END, USER_SPACE_START};
+        use crate::page_fault::vma_to_page_table_flags;
+        use x86_64::structures::paging::{Page, PageSize, Size4KiB};
+        use x86_64::VirtAddr;
 
-        let page_table_ptr: *mut PageTable = unsafe { cr3_virt.as_mut_ptr() };
-        let page_table: &mut PageTable = unsafe { &mut *page_table_ptr };
+        let end = start + shm.size();
+        if start < USER_SPACE_START || end > USER_SPACE_END {
+            return Err(ShmError::OutOfRange);
+        }
+        let backing = VmaBacking::Shared {
+            id: shm.get_id(),
+            offset: 0,
+        };
+        let flags = flags | VmaFlags::USER;
+        self.vmas.insert(Vma::new(start, end, flags, backing))?;
 
-        let mut new_page_table = Box::new(PageTable::new());
-        for (i, entry) in page_table.iter().enumerate() {
-            if !entry.is_unused() {
-                new_page_table[i] = entry.clone();
+        let page_table_flags = vma_to_page_table_flags(flags) | SHARED;
+        let l4_frame = self.get_page_table();
+        for i in 0..shm.page_num() {
+            let page = Page::containing_address(VirtAddr::new(start + i as u64 * Size4KiB::SIZE));
+            let mapped = match shm.frame(i) {
+                Some(frame) => map_user_frame(l4_frame, page, frame, page_table_flags),
+                None => false,
+            };
+            if !mapped {
+                let _ = self.unmap(start, end);
+                return Err(ShmError::MapFailed);
             }
         }
-
-        let new_page_table_addr = Box::into_raw(new_page_table) as usize;
-        unsafe { virt2phys(new_page_table_addr, PHYSICAL_MEMORY_OFFSET) }
+        Ok(())
     }
 
-    // NOTE: mostly copied from 611
-    pub fn set_context(&mut self, tfunction: *const fn()) {
-        unsafe {
-            self.context.push_stack(0);
-            self.context.push_stack(thread_shutdown as usize);
-            self.context.push_stack(tfunction as usize);
-            self.context.push_stack(thread_start as usize);
+    // remove [start, end) from the address space, whatever is mapped there is unmapped
+    pub fn unmap(&mut self, start: u64, end: u64) -> Result<(), VmaError> {
+        use crate::address_space::unmap_user_range;
+
+        for vma in self.vmas.remove(start, end)? {
+            unmap_user_range(self.get_page_table(), vma.start, vma.end);
         }
+        Ok(())
+    }
+
+    // The process are created from the kernel process
+    // the kernel half of the current page table is shared,
+    // the user half starts out empty (see `address_space` for the layout)
+    fn init_page_table() -> usize {
+        use crate::address_space::new_user_l4;
+
+        let l4_frame = new_user_l4().expect("out of memory while creating a page table");
+        l4_frame.start_address().as_u64() as usize
+    }
+
+    // the process starts out in `main` the first time it is switched to
+    // and exits once `main` returns, see `thread::spawn` for a handle to join it
+    pub fn set_context(&mut self, main: Box<dyn FnOnce() + Send>) {
+        // a fat pointer does not fit in a register, it is boxed once more
+        let main = Box::into_raw(Box::new(main));
+        unsafe { self.context.set_entry(thread_entry, main as usize) };
     }
 
-    #[cold]
-    #[inline(never)]
-    #[naked]
     pub fn switch_process(&mut self, nextp: &mut Self) {
-        unsafe {
-            if self.pid != nextp.pid {
-                self.context.switch_to(&mut nextp.context);
-
-                //dealing with iretq stack layout
-                // ss
-                // rsp
-                // rflags
-                // cs
-                // iretq
-                let rip = nextp.context.pop_stack();
-                asm!("push 0
-                    push $0
-                    pushfq
-                    push 8
-                    push $1
-                    iretq"
-                    : : "r"(nextp.context.get_rsp()), "r"(rip): "memory" : "intel", "volatile", "alignstack");
-            }
+        if self.pid != nextp.pid {
+            unsafe { self.context.switch_to(&nextp.context) };
         }
     }
 
@@ -107,22 +235,126 @@ impl Process {
         self.pid
     }
 
+    // the frame of the level 4 page table
+    pub fn get_page_table(&self) -> PhysFrame {
+        tlb::cr3_frame(self.context.get_cr3())
+    }
+
+    pub fn get_pcid(&self) -> Option<u16> {
+        self.pcid
+    }
+
+    // load the address space of this process into CR3
+    // with a PCID, the TLB entries cached for this process survive the switch
+    pub fn activate_address_space(&self) {
+        tlb::switch_address_space(self.get_page_table(), self.pcid);
+    }
+
+    // make `nextp` the active process, returns once the calling process is switched back to
+    // must be called with interrupts disabled, the scheduler picks who runs next
     pub fn dispatch_to(nextp: &mut Self) {
         let active_process: &mut Self = unsafe { &mut *ACTIVE_PROCESS };
+        // `switch_to` loads this value into CR3 as it is
+        let cr3 = tlb::cr3_for_switch(nextp.get_page_table(), nextp.pcid);
+        nextp.context.set_cr3(cr3);
+        unsafe { ACTIVE_PROCESS = nextp };
         active_process.switch_process(nextp);
     }
 
+    pub fn get_state(&self) -> ProcessState {
+        self.state
+    }
+
+    pub fn sched_info(&mut self) -> &mut SchedInfo {
+        &mut self.sched
+    }
+
+    // before the process is handed to the scheduler, `scheduler::set_priority` afterwards
+    pub fn set_priority(&mut self, priority: usize) {
+        self.sched.set_priority(priority);
+    }
+
+    pub fn set_nice(&mut self, nice: i8) {
+        self.sched.set_nice(nice);
+    }
+
+    pub fn get_parent(&self) -> usize {
+        self.parent
+    }
+
+    // orphans are handed to the kernel process
+    pub fn set_parent(&mut self, parent: usize) {
+        self.parent = parent;
+    }
+
+    pub fn is_detached(&self) -> bool {
+        self.detached
+    }
+
+    pub fn detach(&mut self) {
+        self.detached = true;
+    }
+
+    // None until the process exited
+    pub fn get_exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    // `hook` runs once the process exited, however it exited
+    pub fn set_exit_hook(&mut self, hook: ExitHook) {
+        self.exit_hook = Some(hook);
+    }
+
+    // called by the scheduler on the way out
+    pub fn set_exit_code(&mut self, exit_code: i32) -> Option<ExitHook> {
+        self.exit_code = Some(exit_code);
+        self.exit_hook.take()
+    }
+
     // Not sure if the static lifetime is proper
     pub fn get_active_process() -> &'static Self {
         unsafe { &*ACTIVE_PROCESS }
     }
 }
 
-// NOTE: Copied from 611
-fn thread_start() {
-    println!("Thread Start!");
+// NOTE: the private half of the address space goes away with the process,
+// the kernel stack is freed when `context` is dropped
+// an exited process is dropped by the reaper, once it is no longer running on that stack
+impl Drop for Process {
+    fn drop(&mut self) {
+        use crate::address_space::destroy_address_space;
+
+        // the kernel process uses the page table of the whole kernel
+        if self.pid == 0 {
+            return;
+        }
+        if let Some(pcid) = self.pcid {
+            tlb::free_pcid(pcid);
+        }
+        crate::swap::unregister_address_space(self.get_page_table());
+        destroy_address_space(self.get_page_table());
+        // an exited process keeps its pid until the exit code is collected
+        if self.exit_code.is_none() || self.detached {
+            free_pid(self.pid);
+        }
+    }
 }
 
-fn thread_shutdown() {
-    println!("Thread Shutdown!");
-}
\ No newline at end of file
+// NOTE: called on the kernel stack of the active process once it cannot go on,
+// e.g. once its thread function returned
+// the process becomes a zombie, the scheduler switches away for good and the reaper frees it
+pub fn exit_active_process(exit_code: i32) -> ! {
+    if let Some(process) = unsafe { ACTIVE_PROCESS.as_ref() } {
+        println!("Process {} exited with {}", process.get_pid(), exit_code);
+    }
+    crate::scheduler::exit(exit_code)
+}
+
+// the first switch to a process returns here, see `Context::set_entry`
+extern "C" fn thread_entry(main: usize) -> ! {
+    // switches happen with interrupts disabled
+    interrupts::enable();
+    let main = unsafe { Box::from_raw(main as *mut Box<dyn FnOnce() + Send>) };
+    main();
+    exit_active_process(0)
+}
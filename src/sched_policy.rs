@@ -0,0 +1,392 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+// NOTE: scheduling policies
+//
+// The scheduler does the switching, a policy only decides who runs next and for how long.
+// It keeps the runnable tasks (the active one is handed back with `enqueue` before every
+// pick), is told about every tick the active task runs and about tasks giving up the CPU
+// early. A task carries its own bookkeeping in a `SchedInfo`.
+//
+// `Mlfq` is a multi-level feedback queue:
+// - a task runs at a level, the lower the level the higher its priority, and tasks of a
+//   level only run if all levels above are empty. tasks of a level take turns
+// - the slice of a task grows with its level and is scaled by its nice value
+// - a task using up its slice is CPU-bound and is demoted one level. the ticks are summed
+//   up over all runs at a level, yielding right before the slice is used up does not help
+// - a task giving up the CPU almost right after it was picked, e.g. to wait for input,
+//   is I/O-bound and is boosted one level
+// - every `boost_interval` ticks all tasks go back to their priority, CPU-bound tasks
+//   that became interactive are not stuck at the bottom, and nothing starves there
+// A task never runs above the level of its priority.
+pub const MLFQ_LEVELS: usize = 4;
+// in ticks, the top level is the shortest
+pub const MLFQ_SLICES: [usize; MLFQ_LEVELS] = [5, 10, 20, 40];
+pub const BOOST_INTERVAL_TICKS: usize = 1000;
+// giving up the CPU within this many ticks of being picked makes a task I/O-bound
+pub const IO_BOUND_TICKS: usize = 1;
+// the slice of the round robin policy
+pub const TIME_SLICE_TICKS: usize = 10;
+
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchedInfo {
+    // the highest level the task may run at, 0 is the top level
+    priority: usize,
+    // -20 doubles the slice, 19 cuts it down to a twentieth
+    nice: i8,
+    // the level the task runs at right now
+    level: usize,
+    // ticks run at the current level
+    used: usize,
+    // ticks run since it was picked last
+    ran: usize,
+}
+
+impl Default for SchedInfo {
+    fn default() -> Self {
+        SchedInfo {
+            priority: 0,
+            nice: 0,
+            level: 0,
+            used: 0,
+            ran: 0,
+        }
+    }
+}
+
+impl SchedInfo {
+    pub fn get_priority(&self) -> usize {
+        self.priority
+    }
+
+    // takes effect the next time the task is queued, levels beyond the last one are clamped
+    pub fn set_priority(&mut self, priority: usize) {
+        self.priority = priority.min(MLFQ_LEVELS - 1);
+        self.reset_level();
+    }
+
+    pub fn get_nice(&self) -> i8 {
+        self.nice
+    }
+
+    pub fn set_nice(&mut self, nice: i8) {
+        self.nice = nice.max(NICE_MIN).min(NICE_MAX);
+    }
+
+    pub fn get_level(&self) -> usize {
+        self.level
+    }
+
+    // back to the level of its priority
+    fn reset_level(&mut self) {
+        self.level = self.priority;
+        self.used = 0;
+    }
+
+    // `ticks` scaled by the nice value, at least one tick
+    pub fn scaled_slice(&self, ticks: usize) -> usize {
+        (ticks * (20 - self.nice as isize) as usize / 20).max(1)
+    }
+}
+
+pub trait Schedulable {
+    fn sched_info(&mut self) -> &mut SchedInfo;
+}
+
+pub trait SchedulingPolicy<T: Schedulable> {
+    // `task` is runnable, the active task is queued this way too before the next pick
+    fn enqueue(&mut self, task: T);
+
+    // take the task to run next out of the queue
+    fn pick_next(&mut self) -> Option<T>;
+
+    // the active task ran for another tick, returns true if it should give up the CPU
+    fn tick(&mut self, active: &mut T) -> bool;
+
+    // the active task gives up the CPU by itself, it is queued again once the next task was
+    // picked (unless it blocks)
+    fn yielded(&mut self, active: &mut T);
+
+    // apply `f` to every queued task
+    fn for_each(&mut self, f: &mut dyn FnMut(&mut T));
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub struct RoundRobin<T> {
+    queue: VecDeque<T>,
+}
+
+impl<T> RoundRobin<T> {
+    pub fn new() -> Self {
+        RoundRobin {
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+impl<T> Default for RoundRobin<T> {
+    fn default() -> Self {
+        RoundRobin::new()
+    }
+}
+
+impl<T: Schedulable> SchedulingPolicy<T> for RoundRobin<T> {
+    fn enqueue(&mut self, task: T) {
+        self.queue.push_back(task);
+    }
+
+    fn pick_next(&mut self) -> Option<T> {
+        let mut task = self.queue.pop_front()?;
+        task.sched_info().ran = 0;
+        Some(task)
+    }
+
+    fn tick(&mut self, active: &mut T) -> bool {
+        let info = active.sched_info();
+        info.ran += 1;
+        info.ran >= info.scaled_slice(TIME_SLICE_TICKS)
+    }
+
+    fn yielded(&mut self, _active: &mut T) {}
+
+    fn for_each(&mut self, f: &mut dyn FnMut(&mut T)) {
+        self.queue.iter_mut().for_each(f);
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+pub struct Mlfq<T> {
+    levels: Vec<VecDeque<T>>,
+    boost_interval: usize,
+    // ticks until the next boost
+    boost_left: usize,
+}
+
+impl<T: Schedulable> Mlfq<T> {
+    pub fn new(boost_interval: usize) -> Self {
+        Mlfq {
+            levels: (0..MLFQ_LEVELS).map(|_| VecDeque::new()).collect(),
+            boost_interval: boost_interval,
+            boost_left: boost_interval,
+        }
+    }
+
+    // the slice of `info` at its current level
+    pub fn slice_of(info: &SchedInfo) -> usize {
+        info.scaled_slice(MLFQ_SLICES[info.level])
+    }
+
+    // put every task back at its priority, `active` included
+    fn boost(&mut self, active: &mut T) {
+        let mut tasks = Vec::new();
+        for level in self.levels.iter_mut() {
+            tasks.extend(level.drain(..));
+        }
+        for mut task in tasks {
+            task.sched_info().reset_level();
+            self.enqueue(task);
+        }
+        active.sched_info().reset_level();
+        self.boost_left = self.boost_interval;
+    }
+}
+
+impl<T: Schedulable> Default for Mlfq<T> {
+    fn default() -> Self {
+        Mlfq::new(BOOST_INTERVAL_TICKS)
+    }
+}
+
+impl<T: Schedulable> SchedulingPolicy<T> for Mlfq<T> {
+    fn enqueue(&mut self, mut task: T) {
+        let info = task.sched_info();
+        let level = info.level.max(info.priority);
+        info.level = level;
+        self.levels[level].push_back(task);
+    }
+
+    fn pick_next(&mut self) -> Option<T> {
+        let level = self.levels.iter().position(|level| !level.is_empty())?;
+        let mut task = self.levels[level].pop_front()?;
+        task.sched_info().ran = 0;
+        Some(task)
+    }
+
+    fn tick(&mut self, active: &mut T) -> bool {
+        self.boost_left = self.boost_left.saturating_sub(1);
+        if self.boost_left == 0 {
+            self.boost(active);
+            // whoever is at the top now gets the CPU
+            return true;
+        }
+        let info = active.sched_info();
+        info.ran += 1;
+        info.used += 1;
+        if info.used < Self::slice_of(info) {
+            return false;
+        }
+        // CPU-bound
+        info.level = (info.level + 1).min(MLFQ_LEVELS - 1);
+        info.used = 0;
+        true
+    }
+
+    fn yielded(&mut self, active: &mut T) {
+        let info = active.sched_info();
+        if info.ran <= IO_BOUND_TICKS && info.level > info.priority {
+            info.level -= 1;
+            info.used = 0;
+        }
+    }
+
+    fn for_each(&mut self, f: &mut dyn FnMut(&mut T)) {
+        for level in self.levels.iter_mut() {
+            level.iter_mut().for_each(&mut *f);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.levels.iter().map(|level| level.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Task {
+        id: usize,
+        info: SchedInfo,
+    }
+
+    impl Schedulable for Task {
+        fn sched_info(&mut self) -> &mut SchedInfo {
+            &mut self.info
+        }
+    }
+
+    fn task(id: usize) -> Task {
+        Task {
+            id: id,
+            info: SchedInfo::default(),
+        }
+    }
+
+    // run `active` for `ticks` ticks or until the policy preempts it
+    fn run(policy: &mut Mlfq<Task>, active: &mut Task, ticks: usize) -> bool {
+        (0..ticks).any(|_| policy.tick(active))
+    }
+
+    #[test]
+    fn cpu_bound_tasks_are_demoted() {
+        let mut policy = Mlfq::new(BOOST_INTERVAL_TICKS);
+        policy.enqueue(task(1));
+        policy.enqueue(task(2));
+
+        let mut active = policy.pick_next().unwrap();
+        assert_eq!(active.id, 1);
+        assert!(!run(&mut policy, &mut active, MLFQ_SLICES[0] - 1));
+        assert!(policy.tick(&mut active));
+        assert_eq!(active.info.get_level(), 1);
+        policy.enqueue(active);
+
+        // task 2 is still at the top
+        let active = policy.pick_next().unwrap();
+        assert_eq!(active.id, 2);
+        assert_eq!(active.info.get_level(), 0);
+        policy.enqueue(active);
+        assert_eq!(policy.len(), 2);
+    }
+
+    #[test]
+    fn io_bound_tasks_are_boosted() {
+        let mut policy = Mlfq::new(BOOST_INTERVAL_TICKS);
+        let mut active = task(1);
+        active.info.level = 2;
+        policy.enqueue(active);
+
+        let mut active = policy.pick_next().unwrap();
+        policy.tick(&mut active);
+        policy.yielded(&mut active);
+        assert_eq!(active.info.get_level(), 1);
+
+        // running longer before yielding does not count as I/O-bound
+        policy.enqueue(active);
+        let mut active = policy.pick_next().unwrap();
+        assert!(!run(&mut policy, &mut active, IO_BOUND_TICKS + 1));
+        policy.yielded(&mut active);
+        assert_eq!(active.info.get_level(), 1);
+    }
+
+    #[test]
+    fn yielding_does_not_reset_the_slice() {
+        let mut policy = Mlfq::new(BOOST_INTERVAL_TICKS);
+        policy.enqueue(task(1));
+        let slice = MLFQ_SLICES[0];
+
+        let mut active = policy.pick_next().unwrap();
+        assert!(!run(&mut policy, &mut active, slice - 2));
+        policy.yielded(&mut active);
+        policy.enqueue(active);
+        let mut active = policy.pick_next().unwrap();
+        assert!(!policy.tick(&mut active));
+        assert!(policy.tick(&mut active));
+        assert_eq!(active.info.get_level(), 1);
+    }
+
+    #[test]
+    fn periodic_boost_resets_levels() {
+        let boost_interval = 100;
+        let mut policy = Mlfq::new(boost_interval);
+        let mut low = task(1);
+        low.info.level = MLFQ_LEVELS - 1;
+        policy.enqueue(low);
+        let mut high = task(2);
+        high.info.set_priority(1);
+        policy.enqueue(high);
+
+        // demoted to the bottom on the way
+        let mut active = task(3);
+        active.info.level = 2;
+        for _ in 0..boost_interval - 1 {
+            policy.tick(&mut active);
+        }
+        assert_eq!(active.info.get_level(), MLFQ_LEVELS - 1);
+        assert!(policy.tick(&mut active));
+        assert_eq!(active.info.get_level(), 0);
+        policy.enqueue(active);
+
+        // the task with priority 1 stays below the others
+        let order: Vec<usize> = (0..3).map(|_| policy.pick_next().unwrap().id).collect();
+        assert_eq!(order, [1, 3, 2]);
+    }
+
+    #[test]
+    fn priority_and_nice() {
+        let mut policy = Mlfq::new(BOOST_INTERVAL_TICKS);
+        let mut low = task(1);
+        low.info.set_priority(MLFQ_LEVELS + 5);
+        assert_eq!(low.info.get_priority(), MLFQ_LEVELS - 1);
+        policy.enqueue(low);
+        policy.enqueue(task(2));
+        assert_eq!(policy.pick_next().unwrap().id, 2);
+
+        let mut info = SchedInfo::default();
+        info.set_nice(-100);
+        assert_eq!(info.get_nice(), NICE_MIN);
+        assert_eq!(Mlfq::<Task>::slice_of(&info), MLFQ_SLICES[0] * 2);
+        info.set_nice(NICE_MAX);
+        assert_eq!(Mlfq::<Task>::slice_of(&info), 1);
+    }
+}
@@ -0,0 +1,353 @@
+use crate::process::{free_pid, Process, ProcessState, ACTIVE_PROCESS};
+use crate::sched_policy::{Mlfq, SchedInfo, Schedulable, SchedulingPolicy};
+use crate::softirq;
+use crate::thread;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// NOTE: preemptive scheduling
+//
+// Runnable processes are kept by the scheduling policy (see `sched_policy`), an MLFQ unless
+// another one is set with `set_policy`. Every timer tick is reported to the policy, once it
+// says the active process has run long enough, the active process is handed back to the
+// policy and the one it picks runs next. A process can also give up the CPU with
+// `yield_now`, any other runnable process goes first then, or `block` until another process
+// calls `wake` for it.
+// Preemption happens at the end of the timer interrupt, after the end of interrupt was sent
+// and the deferred work ran, on the kernel stack of the interrupted process. The switch
+// returns once the process is picked again, and the interrupt returns as usual.
+// The policy is only used with interrupts disabled.
+// With nothing runnable the idle process runs, it is never handed to the policy.
+//
+// An exiting process becomes a zombie: its exit code is handed to its parent, which can
+// `wait` for it, and to its exit hook, which is how `JoinHandle::join` learns about it. The
+// process cannot free its own kernel stack while running on it, the reaper thread frees
+// zombies after they were switched away from. The pid stays taken until the parent collected
+// the exit code or exited itself. The children of an exiting process go to the kernel process
+// detached, like threads whose `JoinHandle` was dropped: nobody waits for them, so their pids
+// are freed by the reaper.
+
+// the scheduler owns every process but the active one, which is owned through `ACTIVE_PROCESS`
+pub struct ProcessPtr(*mut Process);
+
+// there is a single CPU and the policy is locked with interrupts disabled
+unsafe impl Send for ProcessPtr {}
+
+impl Schedulable for ProcessPtr {
+    fn sched_info(&mut self) -> &mut SchedInfo {
+        unsafe { (*self.0).sched_info() }
+    }
+}
+
+impl ProcessPtr {
+    pub fn get_pid(&self) -> usize {
+        unsafe { (*self.0).get_pid() }
+    }
+}
+
+pub type Policy = Box<dyn SchedulingPolicy<ProcessPtr> + Send>;
+
+lazy_static! {
+    static ref POLICY: Mutex<Policy> = Mutex::new(Box::new(Mlfq::default()));
+    // processes waiting for `wake`, only locked with interrupts disabled
+    static ref BLOCKED: Mutex<Vec<ProcessPtr>> = Mutex::new(Vec::new());
+    // processes waiting for the reaper, only locked with interrupts disabled
+    static ref ZOMBIES: Mutex<Vec<ProcessPtr>> = Mutex::new(Vec::new());
+}
+
+// set once the policy preempts the active process, the switch happens at the end of the
+// interrupt
+static mut NEED_RESCHED: bool = false;
+static mut SWITCH_NUM: u64 = 0;
+static mut IDLE_PROCESS: *mut Process = core::ptr::null_mut();
+static mut REAPER_PID: usize = 0;
+
+fn idle() {
+    crate::hlt_loop();
+}
+
+// free the zombies, they are no longer running on their kernel stacks
+fn reap() {
+    loop {
+        let zombies = interrupts::without_interrupts(|| loop {
+            let zombies = mem::replace(&mut *ZOMBIES.lock(), Vec::new());
+            if !zombies.is_empty() {
+                break zombies;
+            }
+            block();
+        });
+        for zombie in zombies {
+            drop(unsafe { Box::from_raw(zombie.0) });
+        }
+    }
+}
+
+// the code running since boot becomes the kernel process (pid 0)
+pub fn init() {
+    let mut idle_process = Box::new(Process::new());
+    idle_process.set_context(Box::new(idle));
+    interrupts::without_interrupts(|| unsafe {
+        assert!(ACTIVE_PROCESS.is_null(), "the scheduler is already initialized");
+        ACTIVE_PROCESS = Box::into_raw(Box::new(Process::kernel()));
+        IDLE_PROCESS = Box::into_raw(idle_process);
+    });
+    // detached, it never finishes
+    let reaper = thread::spawn(reap);
+    unsafe { REAPER_PID = reaper.get_pid() };
+}
+
+pub fn is_initialized() -> bool {
+    unsafe { !ACTIVE_PROCESS.is_null() }
+}
+
+// hand a new process to the scheduler, it runs once the policy picks it
+pub fn add_process(mut process: Box<Process>) {
+    process.state = ProcessState::Ready;
+    interrupts::without_interrupts(|| {
+        POLICY.lock().enqueue(ProcessPtr(Box::into_raw(process)));
+    });
+}
+
+// replace the scheduling policy, the queued processes move over to `policy`
+pub fn set_policy(mut policy: Policy) {
+    interrupts::without_interrupts(|| {
+        let mut current = POLICY.lock();
+        while let Some(process) = current.pick_next() {
+            policy.enqueue(process);
+        }
+        *current = policy;
+    });
+}
+
+pub fn ready_num() -> usize {
+    interrupts::without_interrupts(|| POLICY.lock().len())
+}
+
+// the number of context switches so far
+pub fn switch_num() -> u64 {
+    unsafe { SWITCH_NUM }
+}
+
+// exited processes the reaper did not free yet
+pub fn zombie_num() -> usize {
+    interrupts::without_interrupts(|| ZOMBIES.lock().len())
+}
+
+// apply `f` to every process that did not exit
+fn for_each_process<F: FnMut(&mut Process)>(mut f: F) {
+    interrupts::without_interrupts(|| {
+        let active = unsafe { &mut *ACTIVE_PROCESS };
+        if active.state != ProcessState::Zombie {
+            f(active);
+        }
+        let mut apply = |process: &mut ProcessPtr| f(unsafe { &mut *process.0 });
+        POLICY.lock().for_each(&mut apply);
+        BLOCKED.lock().iter_mut().for_each(&mut apply);
+    })
+}
+
+// apply `f` to process `pid` unless it exited, false if there is no such process
+fn with_process<F: FnMut(&mut Process)>(pid: usize, mut f: F) -> bool {
+    let mut found = false;
+    for_each_process(|process| {
+        if process.get_pid() == pid {
+            f(process);
+            found = true;
+        }
+    });
+    found
+}
+
+// the highest level process `pid` may run at, see `SchedInfo::set_priority`
+pub fn set_priority(pid: usize, priority: usize) -> bool {
+    with_process(pid, |process| process.set_priority(priority))
+}
+
+pub fn set_nice(pid: usize, nice: i8) -> bool {
+    with_process(pid, |process| process.set_nice(nice))
+}
+
+// called by the timer interrupt handler on every tick
+pub fn tick() {
+    if !is_initialized() {
+        return;
+    }
+    let mut active = ProcessPtr(unsafe { ACTIVE_PROCESS });
+    if POLICY.lock().tick(&mut active) {
+        unsafe { NEED_RESCHED = true };
+    }
+}
+
+// called at the very end of every interrupt, interrupts are disabled
+// deferred work interrupted by this interrupt must finish first, it runs on the same stack
+pub fn preempt() {
+    if unsafe { !NEED_RESCHED } || softirq::is_running() {
+        return;
+    }
+    schedule(false);
+}
+
+// let the policy pick the next process, the calling process stays runnable
+pub fn yield_now() {
+    interrupts::without_interrupts(|| schedule(true));
+}
+
+// the pid of the active process
+pub fn active_pid() -> usize {
+    unsafe { (*ACTIVE_PROCESS).get_pid() }
+}
+
+// stop running until `wake` is called with the pid of the active process
+// interrupts must be disabled from checking the condition to wait for up to here,
+// so the wakeup cannot happen in between. they are still disabled when it returns
+// a process may be woken for something else, callers check their condition again
+pub fn block() {
+    assert!(!interrupts::are_enabled(), "blocking with interrupts enabled");
+    unsafe { (*ACTIVE_PROCESS).state = ProcessState::Blocked };
+    schedule(true);
+}
+
+// make the blocked process `pid` runnable again, false if it is not blocked
+pub fn wake(pid: usize) -> bool {
+    interrupts::without_interrupts(|| {
+        let process = {
+            let mut blocked = BLOCKED.lock();
+            match blocked.iter().position(|process| process.get_pid() == pid) {
+                Some(idx) => blocked.swap_remove(idx),
+                None => return false,
+            }
+        };
+        unsafe {
+            (*process.0).state = ProcessState::Ready;
+            // the idle process only gives up the CPU when told so
+            if ACTIVE_PROCESS == IDLE_PROCESS {
+                NEED_RESCHED = true;
+            }
+        }
+        POLICY.lock().enqueue(process);
+        true
+    })
+}
+
+// take the exit code of the child `pid` if it exited, its pid is free again afterwards
+pub fn try_wait(pid: usize) -> Option<i32> {
+    interrupts::without_interrupts(|| {
+        let exited = unsafe { &mut (*ACTIVE_PROCESS).exited_children };
+        let idx = exited.iter().position(|&(child, _)| child == pid)?;
+        free_pid(pid);
+        Some(exited.remove(idx).1)
+    })
+}
+
+// wait for the child `pid` to exit and take its exit code
+// None if the active process has no such child, or its exit code was taken already
+pub fn wait(pid: usize) -> Option<i32> {
+    interrupts::without_interrupts(|| loop {
+        if let Some(exit_code) = try_wait(pid) {
+            return Some(exit_code);
+        }
+        let active_pid = active_pid();
+        let mut is_child = false;
+        if !with_process(pid, |process| is_child = process.get_parent() == active_pid) {
+            return None;
+        }
+        if !is_child {
+            return None;
+        }
+        block();
+    })
+}
+
+// the exit code of process `pid` will not be collected, its pid is freed once it is reaped
+// false if there is no such process
+pub fn detach(pid: usize) -> bool {
+    with_process(pid, |process| process.detach())
+}
+
+// switch away from the active process for good, it becomes a zombie until it is reaped
+pub fn exit(exit_code: i32) -> ! {
+    if !is_initialized() {
+        crate::hlt_loop();
+    }
+    interrupts::disable();
+    let active = unsafe { &mut *ACTIVE_PROCESS };
+    let (pid, parent) = (active.get_pid(), active.get_parent());
+    assert!(pid != 0, "the kernel process cannot exit");
+    active.state = ProcessState::Zombie;
+    if let Some(exit_hook) = active.set_exit_code(exit_code) {
+        exit_hook(exit_code);
+    }
+    // nobody collects the exit codes of the children any more
+    for (child, _) in active.exited_children.drain(..) {
+        free_pid(child);
+    }
+    for_each_process(|process| {
+        if process.get_parent() == pid {
+            process.set_parent(0);
+            process.detach();
+        }
+    });
+    // the parent is still there, it would have taken over its children otherwise
+    let hand_over = |parent: &mut Process| parent.exited_children.push((pid, exit_code));
+    if !active.is_detached() && with_process(parent, hand_over) {
+        wake(parent);
+    }
+    wake(unsafe { REAPER_PID });
+    schedule(false);
+    unreachable!("an exited process was switched back to");
+}
+
+// interrupts must be disabled
+// `yielded` is true if the active process gives up the CPU by itself
+fn schedule(yielded: bool) {
+    unsafe { NEED_RESCHED = false };
+    let idle_process = unsafe { IDLE_PROCESS };
+    let mut active = ProcessPtr(unsafe { ACTIVE_PROCESS });
+    let active_state = unsafe { (*active.0).state };
+    let mut policy = POLICY.lock();
+    if yielded && active_state != ProcessState::Zombie {
+        policy.yielded(&mut active);
+    }
+    // a process yielding is queued after the pick, so it only runs again if nobody else can.
+    // otherwise it would be picked right away if it is on the highest non-empty level
+    let mut yielding = None;
+    match active_state {
+        _ if active.0 == idle_process => unsafe { (*active.0).state = ProcessState::Ready },
+        ProcessState::Running => {
+            unsafe { (*active.0).state = ProcessState::Ready };
+            if yielded {
+                yielding = Some(active);
+            } else {
+                policy.enqueue(active);
+            }
+        }
+        ProcessState::Blocked => BLOCKED.lock().push(active),
+        // the reaper cannot run before the switch, interrupts are disabled until then
+        ProcessState::Zombie => ZOMBIES.lock().push(active),
+        _ => (),
+    }
+    let mut next = policy.pick_next();
+    if let Some(yielding) = yielding {
+        policy.enqueue(yielding);
+        if next.is_none() {
+            next = policy.pick_next();
+        }
+    }
+    let next = match next {
+        Some(next) => next.0,
+        None => idle_process,
+    };
+    // the lock is released before switching, the next process does not hold it
+    drop(policy);
+    let next = unsafe { &mut *next };
+    next.state = ProcessState::Running;
+    if next as *mut Process != unsafe { ACTIVE_PROCESS } {
+        unsafe { SWITCH_NUM += 1 };
+    }
+    Process::dispatch_to(next);
+}
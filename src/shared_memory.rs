@@ -0,0 +1,185 @@
+use crate::address_space::frame_ptr;
+use crate::data_structures::AvlTree;
+use crate::frame_allocator::get_frame_allocator;
+use crate::vma::VmaError;
+
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+// NOTE: shared memory objects
+//
+// An object is a list of frames that several processes can map, each at its own address
+// and with its own permissions (see `Process::map_shared`). Frames are reference counted
+// through `FrameInfo`: the object holds one reference to each of its frames while a handle
+// is open, and every page table entry mapping a frame holds another one.
+// When the last handle is dropped, the object disappears and its frames are freed as soon
+// as the last mapping is gone.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShmError {
+    Vma(VmaError),
+    OutOfMemory,
+    // the address range is outside the user half
+    OutOfRange,
+    // the pages are already mapped
+    MapFailed,
+    // more pages than the frame list of an object can hold
+    TooLarge,
+}
+
+impl From<VmaError> for ShmError {
+    fn from(error: VmaError) -> Self {
+        ShmError::Vma(error)
+    }
+}
+
+// the frames are listed in index frames, the heap cannot hold a list of more than 512
+struct SharedObject {
+    page_num: usize,
+    index_frames: Vec<PhysFrame>,
+    // number of open `SharedMemory` handles
+    handles: usize,
+}
+
+const FRAMES_PER_INDEX: usize = Size4KiB::SIZE as usize / core::mem::size_of::<u64>();
+// the index frames themselves are listed on the heap
+const MAX_SHM_PAGES: usize = FRAMES_PER_INDEX * FRAMES_PER_INDEX;
+
+impl SharedObject {
+    fn index_entry(&self, i: usize) -> *mut u64 {
+        let index = frame_ptr(self.index_frames[i / FRAMES_PER_INDEX]) as *mut u64;
+        unsafe { index.add(i % FRAMES_PER_INDEX) }
+    }
+
+    fn frame(&self, i: usize) -> Option<PhysFrame> {
+        if i >= self.page_num {
+            return None;
+        }
+        let addr = PhysAddr::new(unsafe { *self.index_entry(i) });
+        Some(PhysFrame::containing_address(addr))
+    }
+}
+
+// the object goes away with its last handle, the frames once they are no longer mapped
+impl Drop for SharedObject {
+    fn drop(&mut self) {
+        for i in 0..self.page_num {
+            release_frame(self.frame(i).unwrap());
+        }
+        let frame_allocator = get_frame_allocator();
+        for &frame in self.index_frames.iter() {
+            frame_allocator.deallocate_frame(frame);
+        }
+    }
+}
+
+lazy_static! {
+    static ref SHARED_OBJECTS: Mutex<AvlTree<usize, SharedObject>> = Mutex::new(AvlTree::new());
+}
+
+static mut NEXT_SHM_ID: usize = 0;
+
+// a handle to a shared memory object
+pub struct SharedMemory {
+    id: usize,
+    size: u64,
+}
+
+fn release_frame(frame: PhysFrame) {
+    let frame_allocator = get_frame_allocator();
+    if let Some(frame_info) = frame_allocator.frame_info(frame) {
+        if frame_info.dec_count() == 0 {
+            frame_allocator.deallocate_frame(frame);
+        }
+    }
+}
+
+impl SharedMemory {
+    // a new object of `page_num` zeroed pages
+    pub fn new(page_num: usize) -> Result<Self, ShmError> {
+        if page_num > MAX_SHM_PAGES {
+            return Err(ShmError::TooLarge);
+        }
+        let frame_allocator = get_frame_allocator();
+        // dropped on failure, which gives back what was taken so far
+        let mut object = SharedObject {
+            page_num: 0,
+            index_frames: Vec::new(),
+            handles: 1,
+        };
+        for i in 0..page_num {
+            if i % FRAMES_PER_INDEX == 0 {
+                let index = frame_allocator
+                    .allocate_frame()
+                    .ok_or(ShmError::OutOfMemory)?;
+                object.index_frames.push(index);
+            }
+            let frame = frame_allocator
+                .alloc_zeroed_frame()
+                .ok_or(ShmError::OutOfMemory)?;
+            if let Some(frame_info) = frame_allocator.frame_info(frame) {
+                frame_info.inc_count();
+            }
+            unsafe { *object.index_entry(i) = frame.start_address().as_u64() };
+            object.page_num += 1;
+        }
+
+        // FIXME: data race here?
+        unsafe { NEXT_SHM_ID += 1 };
+        let id = unsafe { NEXT_SHM_ID };
+        SHARED_OBJECTS.lock().insert(id, object);
+        Ok(SharedMemory {
+            id: id,
+            size: page_num as u64 * Size4KiB::SIZE,
+        })
+    }
+
+    // another handle to the object `id`, e.g. for a process that received the id
+    pub fn open(id: usize) -> Option<Self> {
+        let mut objects = SHARED_OBJECTS.lock();
+        let object = objects.get_mut(&id)?;
+        object.handles += 1;
+        Some(SharedMemory {
+            id: id,
+            size: object.page_num as u64 * Size4KiB::SIZE,
+        })
+    }
+
+    pub fn get_id(&self) -> usize {
+        self.id
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn page_num(&self) -> usize {
+        (self.size / Size4KiB::SIZE) as usize
+    }
+
+    // the frame backing page `i` of the object
+    pub fn frame(&self, i: usize) -> Option<PhysFrame> {
+        let objects = SHARED_OBJECTS.lock();
+        objects.get(&self.id)?.frame(i)
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        let mut objects = SHARED_OBJECTS.lock();
+        let last = match objects.get_mut(&self.id) {
+            Some(object) => {
+                object.handles -= 1;
+                object.handles == 0
+            }
+            None => false,
+        };
+        if !last {
+            return;
+        }
+        objects.remove(&self.id);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::data_structures::RingBuffer;
+use crate::irq;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
+
+// NOTE: deferred interrupt work
+//
+// Interrupt handlers run with interrupts disabled, so they only do what cannot wait
+// (reading the device, acknowledging it) and queue the rest as a work item. The queue is
+// run after the end of interrupt was sent, with interrupts enabled again, so further
+// interrupts are taken while the work runs. Interrupts arriving meanwhile only queue more
+// work, the outermost run picks it up; work items never run nested in each other.
+//
+// Work items must not take locks that the interrupted code may hold with interrupts enabled.
+// They may use the heap, the allocator itself runs with interrupts disabled (see `main.rs`).
+pub type WorkFn = fn(data: usize);
+
+#[derive(Clone, Copy)]
+struct WorkItem {
+    func: WorkFn,
+    data: usize,
+    // the IRQ that queued the work, if any
+    irq: Option<u8>,
+}
+
+fn no_work(_data: usize) {}
+
+impl Default for WorkItem {
+    fn default() -> Self {
+        WorkItem {
+            func: no_work,
+            data: 0,
+            irq: None,
+        }
+    }
+}
+
+lazy_static! {
+    static ref WORK_QUEUE: RingBuffer<WorkItem> = RingBuffer::new();
+}
+
+static mut RUNNING_WORK: bool = false;
+static DROPPED_WORK: AtomicUsize = AtomicUsize::new(0);
+
+// queue `func(data)` to run after the current interrupt, or after the next one if called
+// outside of interrupt handlers. returns false if the queue is full and the work is dropped
+pub fn queue_work(func: WorkFn, data: usize) -> bool {
+    // the queue has a single producer, interrupts are disabled while pushing
+    interrupts::without_interrupts(|| {
+        let item = WorkItem {
+            func: func,
+            data: data,
+            irq: irq::current_irq(),
+        };
+        if WORK_QUEUE.push(item) {
+            if let Some(irq) = item.irq {
+                irq::count_deferred(irq);
+            }
+            true
+        } else {
+            DROPPED_WORK.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    })
+}
+
+pub fn pending_work() -> usize {
+    WORK_QUEUE.len()
+}
+
+pub fn dropped_work() -> usize {
+    DROPPED_WORK.load(Ordering::Relaxed)
+}
+
+// true while work items run, an interrupt taken meanwhile returns into them
+pub fn is_running() -> bool {
+    unsafe { RUNNING_WORK }
+}
+
+// called at the end of every interrupt after the end of interrupt, interrupts are disabled
+// and are disabled again when it returns
+pub fn run_pending() {
+    unsafe {
+        if RUNNING_WORK || WORK_QUEUE.is_empty() {
+            return;
+        }
+        RUNNING_WORK = true;
+    }
+    loop {
+        // the queue is checked with interrupts disabled, work queued by an interrupt
+        // right after the last item is not left behind
+        interrupts::disable();
+        let item = match WORK_QUEUE.pop() {
+            Some(item) => item,
+            None => break,
+        };
+        interrupts::enable();
+        (item.func)(item.data);
+    }
+    unsafe { RUNNING_WORK = false };
+}
@@ -0,0 +1,372 @@
+use crate::address_space::{
+    active_l4_frame, frame_ptr, is_kernel_l4_slot, leaf_entry, table_of, COW, SHARED, SWAPPED,
+};
+use crate::frame_allocator::{get_frame_allocator, set_reclaim_hook};
+use crate::kernel_stack::MAX_KERNEL_STACKS;
+use crate::tlb::TlbBatch;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageSize, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// NOTE: swapping of anonymous user pages
+//
+// When the frame allocator runs dry it calls `reclaim`, which looks for cold pages in the
+// registered address spaces. A page is cold if its ACCESSED bit is clear; pages that were
+// accessed get the bit cleared and a second chance. A cold page is written to a slot of the
+// swap device and its entry becomes non-present:
+//
+//   | swap slot (bits 12..51) | SWAPPED | flags of the page without PRESENT |
+//
+// The next access faults and `swap_in` reads the page back into a fresh frame.
+// Only private pages are swapped: shared memory, copy-on-write pages and frames mapped by
+// more than one entry stay in memory. A forked address space shares the swap slots of its
+// parent, every slot counts the entries referring to it.
+
+pub trait SwapDevice: Send {
+    // the number of page sized slots
+    fn slot_num(&self) -> usize;
+    fn read_page(&mut self, slot: usize, buf: *mut u8);
+    fn write_page(&mut self, slot: usize, buf: *const u8);
+}
+
+// a swap device in memory, its frames are taken from the frame allocator up front
+// the addresses of those frames are kept in index frames, the heap cannot hold more than
+// 4KiB in one piece
+pub struct RamDisk {
+    page_num: usize,
+    index_frames: Vec<PhysFrame>,
+}
+
+const FRAMES_PER_INDEX: usize = Size4KiB::SIZE as usize / core::mem::size_of::<u64>();
+
+impl RamDisk {
+    pub fn new(page_num: usize) -> Option<Self> {
+        let frame_allocator = get_frame_allocator();
+        // dropped on failure, which gives back what was taken so far
+        let mut disk = RamDisk {
+            page_num: 0,
+            index_frames: Vec::new(),
+        };
+        for slot in 0..page_num {
+            if slot % FRAMES_PER_INDEX == 0 {
+                disk.index_frames.push(frame_allocator.allocate_frame()?);
+            }
+            let frame = frame_allocator.allocate_frame()?;
+            unsafe { *disk.index_entry(slot) = frame.start_address().as_u64() };
+            disk.page_num += 1;
+        }
+        Some(disk)
+    }
+
+    fn index_entry(&self, slot: usize) -> *mut u64 {
+        let index = frame_ptr(self.index_frames[slot / FRAMES_PER_INDEX]) as *mut u64;
+        unsafe { index.add(slot % FRAMES_PER_INDEX) }
+    }
+
+    fn frame_of(&self, slot: usize) -> PhysFrame {
+        assert!(slot < self.page_num, "swap slot out of range");
+        PhysFrame::containing_address(PhysAddr::new(unsafe { *self.index_entry(slot) }))
+    }
+}
+
+impl Drop for RamDisk {
+    fn drop(&mut self) {
+        let frame_allocator = get_frame_allocator();
+        for slot in 0..self.page_num {
+            frame_allocator.deallocate_frame(self.frame_of(slot));
+        }
+        for &frame in self.index_frames.iter() {
+            frame_allocator.deallocate_frame(frame);
+        }
+    }
+}
+
+impl SwapDevice for RamDisk {
+    fn slot_num(&self) -> usize {
+        self.page_num
+    }
+
+    fn read_page(&mut self, slot: usize, buf: *mut u8) {
+        let src = frame_ptr(self.frame_of(slot));
+        unsafe { core::ptr::copy_nonoverlapping(src, buf, Size4KiB::SIZE as usize) };
+    }
+
+    fn write_page(&mut self, slot: usize, buf: *const u8) {
+        let dst = frame_ptr(self.frame_of(slot));
+        unsafe { core::ptr::copy_nonoverlapping(buf, dst, Size4KiB::SIZE as usize) };
+    }
+}
+
+struct SwapSpace {
+    device: Box<dyn SwapDevice>,
+    // the number of page table entries referring to each slot, 0 if the slot is free
+    counts: Vec<u16>,
+}
+
+impl SwapSpace {
+    fn alloc_slot(&mut self) -> Option<usize> {
+        let slot = self.counts.iter().position(|&count| count == 0)?;
+        self.counts[slot] = 1;
+        Some(slot)
+    }
+}
+
+// both are only locked with interrupts disabled,
+// the page fault handler and the frame allocator need them
+lazy_static! {
+    static ref SWAP: Mutex<Option<SwapSpace>> = Mutex::new(None);
+}
+
+// the level 4 tables of the address spaces `reclaim` takes pages from, 0 for a free entry
+// every address space belongs to a process, which has a kernel stack, so there are no more
+// address spaces than kernel stacks. only changed with interrupts disabled
+static mut ADDRESS_SPACES: [u64; MAX_KERNEL_STACKS] = [0; MAX_KERNEL_STACKS];
+
+// 2 bytes of slot count each
+const MAX_SWAP_SLOTS: usize = 2048;
+
+// `reclaim` may be entered again through the frame allocator
+static mut RECLAIMING: bool = false;
+
+pub fn init(device: Box<dyn SwapDevice>) {
+    let slot_num = device.slot_num();
+    // the slot counts must fit into the largest heap object
+    assert!(slot_num <= MAX_SWAP_SLOTS, "the swap device has too many slots");
+    *SWAP.lock() = Some(SwapSpace {
+        device: device,
+        counts: vec![0; slot_num],
+    });
+    set_reclaim_hook(reclaim);
+}
+
+pub fn register_address_space(l4_frame: PhysFrame) {
+    without_interrupts(|| unsafe {
+        let free = ADDRESS_SPACES
+            .iter()
+            .position(|&addr| addr == 0)
+            .expect("more address spaces than kernel stacks");
+        ADDRESS_SPACES[free] = l4_frame.start_address().as_u64();
+    });
+}
+
+pub fn unregister_address_space(l4_frame: PhysFrame) {
+    let addr = l4_frame.start_address().as_u64();
+    without_interrupts(|| unsafe {
+        if let Some(idx) = ADDRESS_SPACES.iter().position(|&other| other == addr) {
+            ADDRESS_SPACES[idx] = 0;
+        }
+    });
+}
+
+// the swap slot of a swapped out entry
+pub fn slot_of(entry: &PageTableEntry) -> Option<usize> {
+    let flags = entry.flags();
+    if flags.contains(PageTableFlags::PRESENT) || !flags.contains(SWAPPED) {
+        return None;
+    }
+    Some((entry.addr().as_u64() / Size4KiB::SIZE) as usize)
+}
+
+// another entry refers to `slot`, e.g. after a fork
+pub fn dup_slot(slot: usize) {
+    without_interrupts(|| {
+        if let Some(space) = SWAP.lock().as_mut() {
+            space.counts[slot] += 1;
+        }
+    });
+}
+
+// an entry referring to `slot` is gone
+pub fn free_slot(slot: usize) {
+    without_interrupts(|| {
+        if let Some(space) = SWAP.lock().as_mut() {
+            if space.counts[slot] > 0 {
+                space.counts[slot] -= 1;
+            }
+        }
+    });
+}
+
+pub fn used_slots() -> usize {
+    without_interrupts(|| match SWAP.lock().as_ref() {
+        Some(space) => space.counts.iter().filter(|&&count| count > 0).count(),
+        None => 0,
+    })
+}
+
+pub fn is_swapped_out(l4_frame: PhysFrame, addr: VirtAddr) -> bool {
+    match leaf_entry(l4_frame, addr) {
+        Some(entry) => slot_of(entry).is_some(),
+        None => false,
+    }
+}
+
+// write the page mapped by `entry` to the swap device and free its frame
+// returns false if the page cannot be swapped out, interrupts must be disabled
+fn evict(entry: &mut PageTableEntry, addr: VirtAddr, batch: &mut TlbBatch) -> bool {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE)
+        || flags.intersects(SHARED | COW | PageTableFlags::HUGE_PAGE)
+    {
+        return false;
+    }
+    let frame = match entry.frame() {
+        Ok(frame) => frame,
+        Err(_) => return false,
+    };
+    let frame_allocator = get_frame_allocator();
+    let frame_info = match frame_allocator.frame_info(frame) {
+        Some(frame_info) if frame_info.get_count() == 1 => frame_info,
+        _ => return false,
+    };
+
+    let slot = {
+        let mut swap = SWAP.lock();
+        let space = match swap.as_mut() {
+            Some(space) => space,
+            None => return false,
+        };
+        let slot = match space.alloc_slot() {
+            Some(slot) => slot,
+            None => return false,
+        };
+        space.device.write_page(slot, frame_ptr(frame));
+        slot
+    };
+
+    let swapped_flags = (flags
+        - PageTableFlags::PRESENT
+        - PageTableFlags::ACCESSED
+        - PageTableFlags::DIRTY)
+        | SWAPPED;
+    entry.set_addr(PhysAddr::new(slot as u64 * Size4KiB::SIZE), swapped_flags);
+    batch.add(addr);
+    frame_info.dec_count();
+    frame_allocator.deallocate_frame(frame);
+    true
+}
+
+// swap out the page at `addr` right away, whether it is cold or not
+pub fn swap_out(l4_frame: PhysFrame, addr: VirtAddr) -> bool {
+    let entry = match leaf_entry(l4_frame, addr) {
+        Some(entry) => entry,
+        None => return false,
+    };
+    let mut batch = TlbBatch::for_address_space(l4_frame);
+    without_interrupts(|| evict(entry, addr, &mut batch))
+}
+
+// read the page at `addr` back from the swap device
+// called by the page fault handler with interrupts disabled,
+// returns false if there is no frame for it
+pub fn swap_in(l4_frame: PhysFrame, addr: VirtAddr) -> bool {
+    // allocate before taking the lock, the allocation may have to reclaim pages itself
+    let frame_allocator = get_frame_allocator();
+    let frame = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    let entry = match leaf_entry(l4_frame, addr) {
+        Some(entry) => entry,
+        None => {
+            frame_allocator.deallocate_frame(frame);
+            return false;
+        }
+    };
+    let slot = match slot_of(entry) {
+        Some(slot) => slot,
+        None => {
+            frame_allocator.deallocate_frame(frame);
+            return false;
+        }
+    };
+
+    if let Some(space) = SWAP.lock().as_mut() {
+        space.device.read_page(slot, frame_ptr(frame));
+        if space.counts[slot] > 0 {
+            space.counts[slot] -= 1;
+        }
+    }
+    let flags = (entry.flags() - SWAPPED) | PageTableFlags::PRESENT;
+    entry.set_addr(frame.start_address(), flags);
+    if let Some(frame_info) = frame_allocator.frame_info(frame) {
+        frame_info.inc_count();
+    }
+    if l4_frame == active_l4_frame() {
+        x86_64::instructions::tlb::flush(addr);
+    }
+    true
+}
+
+// try to free `target` frames by swapping out cold pages, returns the number of freed frames
+// the first pass clears the ACCESSED bits, the second one takes the pages not used since then
+// called by the frame allocator with interrupts disabled
+pub fn reclaim(target: usize) -> usize {
+    if unsafe { RECLAIMING } {
+        return 0;
+    }
+    unsafe { RECLAIMING = true };
+
+    let mut freed = 0;
+    for _ in 0..2 {
+        for &addr in unsafe { ADDRESS_SPACES.iter() } {
+            if freed >= target {
+                break;
+            }
+            if addr == 0 {
+                continue;
+            }
+            let l4_frame = PhysFrame::containing_address(PhysAddr::new(addr));
+            let mut batch = TlbBatch::for_address_space(l4_frame);
+            let l4 = unsafe { table_of(l4_frame) };
+            scan_table(l4, 4, 0, target, &mut freed, &mut batch);
+        }
+    }
+
+    unsafe { RECLAIMING = false };
+    freed
+}
+
+fn scan_table(
+    table: &mut PageTable,
+    level: u32,
+    base: u64,
+    target: usize,
+    freed: &mut usize,
+    batch: &mut TlbBatch,
+) {
+    let entry_size: u64 = Size4KiB::SIZE << (9 * (level - 1));
+    for (i, entry) in table.iter_mut().enumerate() {
+        if *freed >= target {
+            return;
+        }
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT)
+            || !flags.contains(PageTableFlags::USER_ACCESSIBLE)
+            || (level == 4 && is_kernel_l4_slot(i))
+        {
+            continue;
+        }
+        let addr = VirtAddr::new(base + i as u64 * entry_size);
+        if level == 1 {
+            if flags.contains(PageTableFlags::ACCESSED) {
+                // give it a second chance
+                entry.set_flags(flags - PageTableFlags::ACCESSED);
+                batch.add(addr);
+            } else if evict(entry, addr, batch) {
+                *freed += 1;
+            }
+        } else if let Ok(frame) = entry.frame() {
+            let next = unsafe { table_of(frame) };
+            scan_table(next, level - 1, addr.as_u64(), target, freed, batch);
+        }
+    }
+}
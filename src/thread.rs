@@ -0,0 +1,114 @@
+use crate::process::Process;
+use crate::scheduler;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// NOTE: kernel threads
+//
+// `spawn` runs a closure in a new process with its own kernel stack. The closure is boxed
+// and handed to the process entry (see `Process::set_context`), its result is left in a
+// packet shared with the `JoinHandle`. `join` blocks until the thread exited, the exit hook
+// of the thread leaves its exit code in the packet and wakes the joiner up. A thread calling
+// `exit_active_process` has no result, `join` returns its exit code instead.
+// Dropping the handle detaches the thread, its result is dropped once it is done and its
+// exit code is not collected (see `scheduler::detach`).
+
+// what the thread and its handle share
+struct Packet<T> {
+    result: Mutex<Option<T>>,
+    // set once the thread exited
+    exit_code: Mutex<Option<i32>>,
+    // the process waiting in `join`
+    joiner: Mutex<Option<usize>>,
+}
+
+pub struct JoinHandle<T> {
+    pid: usize,
+    packet: Arc<Packet<T>>,
+    // the exit code was collected by `join`
+    joined: bool,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn get_pid(&self) -> usize {
+        self.pid
+    }
+
+    // wait for the thread to exit and take its result, or its exit code if it has none
+    // the parent joining the thread collects the exit code too, as `scheduler::wait` does,
+    // the pid may belong to another process afterwards
+    pub fn join(mut self) -> Result<T, i32> {
+        // the exit code and the joiner are looked at with interrupts disabled,
+        // the thread cannot exit between checking for the exit code and blocking
+        interrupts::without_interrupts(|| loop {
+            if let Some(exit_code) = *self.packet.exit_code.lock() {
+                // nothing to take if we are not its parent
+                scheduler::try_wait(self.pid);
+                self.joined = true;
+                return self.packet.result.lock().take().ok_or(exit_code);
+            }
+            *self.packet.joiner.lock() = Some(scheduler::active_pid());
+            scheduler::block();
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        interrupts::without_interrupts(|| self.packet.exit_code.lock().is_some())
+    }
+}
+
+// run `f` in a new kernel thread, it is scheduled like every other process
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = Arc::new(Packet {
+        result: Mutex::new(None),
+        exit_code: Mutex::new(None),
+        joiner: Mutex::new(None),
+    });
+    let thread_packet = packet.clone();
+    let main = move || {
+        let result = f();
+        interrupts::without_interrupts(|| *thread_packet.result.lock() = Some(result));
+    };
+    // runs in `scheduler::exit` with interrupts disabled
+    let hook_packet = packet.clone();
+    let exit_hook = move |exit_code| {
+        *hook_packet.exit_code.lock() = Some(exit_code);
+        if let Some(joiner) = hook_packet.joiner.lock().take() {
+            scheduler::wake(joiner);
+        }
+    };
+
+    let mut process = Box::new(Process::new());
+    process.set_context(Box::new(main));
+    process.set_exit_hook(Box::new(exit_hook));
+    let pid = process.get_pid();
+    scheduler::add_process(process);
+    JoinHandle {
+        pid: pid,
+        packet: packet,
+        joined: false,
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        if self.joined {
+            return;
+        }
+        interrupts::without_interrupts(|| {
+            if self.packet.exit_code.lock().is_some() {
+                // it exited already, the exit code waits for us if we are its parent
+                scheduler::try_wait(self.pid);
+            } else {
+                scheduler::detach(self.pid);
+            }
+        })
+    }
+}
@@ -0,0 +1,135 @@
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+
+// NOTE: monotonic kernel time
+//
+// Channel 0 of the PIT raises IRQ 0 `TICK_HZ` times per second, every interrupt increments
+// the tick counter. Time is counted from the first tick after `init`, so `Instant`s are
+// only comparable within one boot. The resolution is one tick (1ms).
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+pub const TICK_HZ: u64 = 1000;
+pub const NANOS_PER_TICK: u64 = 1_000_000_000 / TICK_HZ;
+
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+// channel 0, low byte then high byte, mode 2 (rate generator), binary
+const PIT_RATE_GENERATOR: u8 = 0x34;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// program the PIT to tick at `TICK_HZ`
+pub fn init() {
+    let divisor = ((PIT_FREQUENCY + TICK_HZ / 2) / TICK_HZ) as u16;
+    let command: Port<u8> = Port::new(PIT_COMMAND);
+    let channel_0: Port<u8> = Port::new(PIT_CHANNEL_0);
+    unsafe {
+        command.write(PIT_RATE_GENERATOR);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+}
+
+// called by the timer interrupt
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn uptime() -> Duration {
+    Instant::now().duration_since(Instant::from_nanos(0))
+}
+
+// a point in time since boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant::from_nanos(ticks() * NANOS_PER_TICK)
+    }
+
+    pub fn from_nanos(nanos: u64) -> Self {
+        Instant { nanos: nanos }
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.nanos
+    }
+
+    // zero if `earlier` is later than `self`
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+fn duration_as_nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + u64::from(duration.subsec_nanos())
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant::from_nanos(self.nanos + duration_as_nanos(duration))
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        Instant::from_nanos(self.nanos.saturating_sub(duration_as_nanos(duration)))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+// wait at least `duration`, halting between timer interrupts
+// interrupts must be enabled, otherwise this never returns
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        x86_64::instructions::hlt();
+    }
+}
+
+// wait at least `duration` without halting, e.g. for device timeouts
+pub fn busy_wait(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn instant_arithmetic() {
+        let start = Instant::from_nanos(5 * NANOS_PER_TICK);
+        let later = start + Duration::from_millis(20);
+        assert_eq!(later.as_nanos(), 25 * NANOS_PER_TICK);
+        assert_eq!(later - start, Duration::from_millis(20));
+        assert_eq!(start - later, Duration::from_nanos(0));
+        assert_eq!(later - Duration::from_secs(1), Instant::from_nanos(0));
+        assert!(start < later);
+    }
+}
@@ -0,0 +1,431 @@
+use crate::data_structures::AvlTree;
+use crate::softirq;
+use crate::time::{self, NANOS_PER_TICK};
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// NOTE: hierarchical timer wheel
+//
+// Level 0 has one slot per tick for the next `WHEEL_SLOTS` ticks, every slot of level 1
+// covers `WHEEL_SLOTS` ticks, every slot of level 2 `WHEEL_SLOTS` level 1 slots and so on.
+// A timer is put on the lowest level whose range reaches its expiry. Whenever the level 0
+// index wraps around, the current slot of level 1 is cascaded: its timers are put into
+// level 0 (or level 1 again), and the same goes on upwards. Arming and cancelling are O(1)
+// (plus the lookup of the timer), a tick only looks at one slot per level.
+//
+//   level 3 | ... |           64^3 ticks per slot
+//   level 2 | ... |           64^2 ticks per slot
+//   level 1 | ... |           64 ticks per slot
+//   level 0 | ... | current | one tick per slot
+//
+// Timers further away than the wheel reaches wait in the last slot of the top level and
+// are cascaded down as the wheel turns. Every timer remembers the slot it waits in, so
+// cancelling or re-arming it takes its entry out of there and the slots do not fill up with
+// stale entries (e.g. of a watchdog that is pushed further out all the time).
+const WHEEL_BITS: u32 = 6;
+const WHEEL_SLOTS: usize = 1 << WHEEL_BITS;
+const WHEEL_LEVELS: usize = 4;
+const MAX_DELTA: u64 = (1 << (WHEEL_BITS * WHEEL_LEVELS as u32)) - 1;
+
+pub type TimerId = u64;
+
+struct TimerEntry<T> {
+    expires: u64,
+    // in ticks, `None` for one-shot timers
+    period: Option<u64>,
+    // bumped whenever the timer is re-armed, older entries in the expired queue are stale
+    version: u64,
+    // level and index of the slot the timer waits in, `None` once it expired
+    slot: Option<(usize, usize)>,
+    // taken out while the timer is being run
+    payload: Option<T>,
+}
+
+// a timer handed out by `pop_expired`, it goes back with `finish`
+pub struct ExpiredTimer<T> {
+    pub id: TimerId,
+    pub payload: T,
+    version: u64,
+}
+
+pub struct TimerWheel<T> {
+    current: u64,
+    // `WHEEL_SLOTS` slots per level, a level is a separate heap block of 1.5KiB
+    // (the heap hands out at most 4KiB in one piece)
+    slots: [Vec<Vec<(TimerId, u64)>>; WHEEL_LEVELS],
+    timers: AvlTree<TimerId, TimerEntry<T>>,
+    expired: VecDeque<(TimerId, u64)>,
+    next_id: TimerId,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(now: u64) -> Self {
+        let mut slots: [Vec<Vec<(TimerId, u64)>>; WHEEL_LEVELS] = Default::default();
+        for level in slots.iter_mut() {
+            *level = (0..WHEEL_SLOTS).map(|_| Vec::new()).collect();
+        }
+        TimerWheel {
+            current: now,
+            slots: slots,
+            timers: AvlTree::new(),
+            expired: VecDeque::new(),
+            next_id: 1,
+        }
+    }
+
+    pub fn current(&self) -> u64 {
+        self.current
+    }
+
+    pub fn timer_num(&self) -> usize {
+        self.timers.size()
+    }
+
+    pub fn is_armed(&self, id: TimerId) -> bool {
+        self.timers.get(&id).is_some()
+    }
+
+    // arm a timer expiring at tick `expires`, periodic timers fire every `period` ticks after
+    pub fn add(&mut self, expires: u64, period: Option<u64>, payload: T) -> TimerId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.timers.insert(
+            id,
+            TimerEntry {
+                expires: expires,
+                period: period.map(|period| period.max(1)),
+                version: 0,
+                slot: None,
+                payload: Some(payload),
+            },
+        );
+        self.enqueue(id, 0, expires);
+        id
+    }
+
+    // returns false if the timer is not armed (any more)
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        match self.timers.remove(&id) {
+            Some(entry) => {
+                self.unlink(id, entry.slot);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // move the (next) expiry of an armed timer to tick `expires`
+    pub fn rearm(&mut self, id: TimerId, expires: u64) -> bool {
+        let (version, slot) = match self.timers.get_mut(&id) {
+            Some(entry) => {
+                entry.version += 1;
+                entry.expires = expires;
+                (entry.version, entry.slot.take())
+            }
+            None => return false,
+        };
+        self.unlink(id, slot);
+        self.enqueue(id, version, expires);
+        true
+    }
+
+    fn is_current(&self, id: TimerId, version: u64) -> bool {
+        match self.timers.get(&id) {
+            Some(entry) => entry.version == version,
+            None => false,
+        }
+    }
+
+    // take the entry of a timer out of the slot it waits in
+    fn unlink(&mut self, id: TimerId, slot: Option<(usize, usize)>) {
+        if let Some((level, index)) = slot {
+            let slot = &mut self.slots[level][index];
+            let pos = slot.iter().position(|&(other, _)| other == id).unwrap();
+            slot.swap_remove(pos);
+        }
+    }
+
+    fn enqueue(&mut self, id: TimerId, version: u64, expires: u64) {
+        if expires <= self.current {
+            self.timers.get_mut(&id).unwrap().slot = None;
+            self.expired.push_back((id, version));
+            return;
+        }
+        let expires = expires.min(self.current + MAX_DELTA);
+        let delta = expires - self.current;
+        let level = (0..WHEEL_LEVELS)
+            .find(|&level| delta < 1 << (WHEEL_BITS * (level as u32 + 1)))
+            .unwrap_or(WHEEL_LEVELS - 1);
+        let index = (expires >> (WHEEL_BITS * level as u32)) as usize & (WHEEL_SLOTS - 1);
+        self.timers.get_mut(&id).unwrap().slot = Some((level, index));
+        self.slots[level][index].push((id, version));
+    }
+
+    // put the timers of the current slot of `level` into the lower levels
+    // returns true if the index of `level` wrapped around as well
+    fn cascade(&mut self, level: usize) -> bool {
+        let index = (self.current >> (WHEEL_BITS * level as u32)) as usize & (WHEEL_SLOTS - 1);
+        let slot = mem::replace(&mut self.slots[level][index], Vec::new());
+        for (id, version) in slot {
+            let expires = self.timers.get(&id).unwrap().expires;
+            self.enqueue(id, version, expires);
+        }
+        index == 0
+    }
+
+    // turn the wheel up to tick `now`, the expired timers are queued for `pop_expired`
+    pub fn advance(&mut self, now: u64) {
+        while self.current < now {
+            self.current += 1;
+            let index = self.current as usize & (WHEEL_SLOTS - 1);
+            if index == 0 {
+                let mut level = 1;
+                while level < WHEEL_LEVELS && self.cascade(level) {
+                    level += 1;
+                }
+            }
+            let slot = mem::replace(&mut self.slots[0][index], Vec::new());
+            for &(id, _) in slot.iter() {
+                self.timers.get_mut(&id).unwrap().slot = None;
+            }
+            self.expired.extend(slot);
+        }
+    }
+
+    pub fn pop_expired(&mut self) -> Option<ExpiredTimer<T>> {
+        while let Some((id, version)) = self.expired.pop_front() {
+            if !self.is_current(id, version) {
+                continue;
+            }
+            if let Some(payload) = self.timers.get_mut(&id).unwrap().payload.take() {
+                return Some(ExpiredTimer {
+                    id: id,
+                    payload: payload,
+                    version: version,
+                });
+            }
+        }
+        None
+    }
+
+    // give back a timer after running it, periodic timers are armed again
+    pub fn finish(&mut self, expired: ExpiredTimer<T>) {
+        let current = self.current;
+        let next = match self.timers.get_mut(&expired.id) {
+            // cancelled in the meantime
+            None => return,
+            Some(entry) => {
+                entry.payload = Some(expired.payload);
+                if entry.version != expired.version {
+                    // re-armed in the meantime
+                    return;
+                }
+                match entry.period {
+                    Some(period) => {
+                        // keep the phase, but do not try to catch up with missed periods
+                        entry.expires = (entry.expires + period).max(current + 1);
+                        entry.version += 1;
+                        Some((entry.version, entry.expires))
+                    }
+                    None => None,
+                }
+            }
+        };
+        match next {
+            Some((version, expires)) => self.enqueue(expired.id, version, expires),
+            None => {
+                self.timers.remove(&expired.id);
+            }
+        }
+    }
+}
+
+// NOTE: kernel timers
+//
+// The wheel turns with the tick counter. Expired callbacks do not run inside the timer
+// interrupt but as deferred work (see `softirq`), with interrupts enabled again.
+// The wheel is only locked with interrupts disabled, so timers may be armed and cancelled
+// from interrupt handlers and from the callbacks themselves.
+pub type TimerCallback = Box<dyn FnMut() + Send>;
+
+lazy_static! {
+    static ref TIMERS: Mutex<TimerWheel<TimerCallback>> = Mutex::new(TimerWheel::new(0));
+}
+
+// the wheel is built, ticks before that (the heap may not be up yet) are ignored
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+// the deferred run of the expired timers is queued, but has not started yet
+static EXPIRY_QUEUED: AtomicBool = AtomicBool::new(false);
+
+// at least one tick, a timer never fires before its delay passed
+fn ticks_of(duration: Duration) -> u64 {
+    let nanos = duration.as_secs() * 1_000_000_000 + u64::from(duration.subsec_nanos());
+    ((nanos + NANOS_PER_TICK - 1) / NANOS_PER_TICK).max(1)
+}
+
+fn with_timers<R, F: FnOnce(&mut TimerWheel<TimerCallback>) -> R>(f: F) -> R {
+    assert!(
+        INITIALIZED.load(Ordering::Acquire),
+        "the timers are not initialized"
+    );
+    interrupts::without_interrupts(|| f(&mut TIMERS.lock()))
+}
+
+// build the wheel, must be called once the heap is up
+pub fn init() {
+    interrupts::without_interrupts(|| lazy_static::initialize(&TIMERS));
+    INITIALIZED.store(true, Ordering::Release);
+}
+
+// run `callback` once after `delay`
+pub fn set_timer<F>(delay: Duration, callback: F) -> TimerId
+where
+    F: FnMut() + Send + 'static,
+{
+    let callback: TimerCallback = Box::new(callback);
+    let expires = time::ticks() + ticks_of(delay);
+    with_timers(|timers| timers.add(expires, None, callback))
+}
+
+// run `callback` every `period`, starting one period from now
+pub fn set_periodic_timer<F>(period: Duration, callback: F) -> TimerId
+where
+    F: FnMut() + Send + 'static,
+{
+    let callback: TimerCallback = Box::new(callback);
+    let period = ticks_of(period);
+    let expires = time::ticks() + period;
+    with_timers(|timers| timers.add(expires, Some(period), callback))
+}
+
+// returns false if the timer already fired (one-shot) or was cancelled
+pub fn cancel_timer(id: TimerId) -> bool {
+    with_timers(|timers| timers.cancel(id))
+}
+
+// fire the timer `delay` from now instead, e.g. to push a watchdog further out
+pub fn rearm_timer(id: TimerId, delay: Duration) -> bool {
+    let expires = time::ticks() + ticks_of(delay);
+    with_timers(|timers| timers.rearm(id, expires))
+}
+
+// called by the timer interrupt
+pub fn schedule_expired() {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return;
+    }
+    if !EXPIRY_QUEUED.swap(true, Ordering::Relaxed) {
+        softirq::queue_work(run_expired, 0);
+    }
+}
+
+fn run_expired(_data: usize) {
+    // ticks from now on queue another run
+    EXPIRY_QUEUED.store(false, Ordering::Relaxed);
+    loop {
+        let expired = with_timers(|timers| {
+            timers.advance(time::ticks());
+            timers.pop_expired()
+        });
+        let mut expired = match expired {
+            Some(expired) => expired,
+            None => break,
+        };
+        (expired.payload)();
+        with_timers(|timers| timers.finish(expired));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fire(wheel: &mut TimerWheel<u32>, now: u64) -> Vec<u32> {
+        wheel.advance(now);
+        let mut fired = Vec::new();
+        while let Some(expired) = wheel.pop_expired() {
+            fired.push(expired.payload);
+            wheel.finish(expired);
+        }
+        fired
+    }
+
+    #[test]
+    fn timers_fire_on_time() {
+        let mut wheel = TimerWheel::new(10);
+        // one timer per level, and one beyond the reach of the wheel
+        let delays = [5u64, 100, 5000, 300_000, MAX_DELTA + 1000];
+        for (i, &delay) in delays.iter().enumerate() {
+            wheel.add(10 + delay, None, i as u32);
+        }
+        let mut now = 10;
+        for (i, &delay) in delays.iter().enumerate() {
+            assert!(fire(&mut wheel, 10 + delay - 1).is_empty());
+            now = 10 + delay;
+            assert_eq!(fire(&mut wheel, now), vec![i as u32]);
+        }
+        assert_eq!(wheel.current(), now);
+        assert_eq!(wheel.timer_num(), 0);
+    }
+
+    #[test]
+    fn cancel_and_rearm() {
+        let mut wheel = TimerWheel::new(0);
+        let cancelled = wheel.add(50, None, 1);
+        let moved = wheel.add(50, None, 2);
+        assert!(wheel.cancel(cancelled));
+        assert!(!wheel.cancel(cancelled));
+        assert!(wheel.rearm(moved, 200));
+        assert!(fire(&mut wheel, 199).is_empty());
+        assert_eq!(fire(&mut wheel, 200), vec![2]);
+        assert!(!wheel.is_armed(moved));
+        // a timer armed in the past fires with the next advance
+        wheel.add(150, None, 3);
+        assert_eq!(fire(&mut wheel, 200), vec![3]);
+    }
+
+    #[test]
+    fn rearm_leaves_no_stale_entries() {
+        let mut wheel = TimerWheel::new(0);
+        let watchdog = wheel.add(100, None, 1);
+        let cancelled = wheel.add(5000, None, 2);
+        for now in 1..=1000 {
+            assert!(fire(&mut wheel, now).is_empty());
+            assert!(wheel.rearm(watchdog, now + 100));
+        }
+        assert!(wheel.cancel(cancelled));
+        let entries: usize = wheel
+            .slots
+            .iter()
+            .flat_map(|level| level.iter())
+            .map(Vec::len)
+            .sum();
+        assert_eq!(entries, 1);
+        assert_eq!(fire(&mut wheel, 1100), vec![1]);
+    }
+
+    #[test]
+    fn periodic_timers() {
+        let mut wheel = TimerWheel::new(0);
+        let id = wheel.add(10, Some(10), 7);
+        let mut count = 0;
+        for now in 1..=100 {
+            count += fire(&mut wheel, now).len();
+        }
+        assert_eq!(count, 10);
+        assert!(wheel.is_armed(id));
+        // missed periods are not made up for
+        assert_eq!(fire(&mut wheel, 1000).len(), 1);
+        assert!(wheel.cancel(id));
+        assert!(fire(&mut wheel, 2000).is_empty());
+    }
+}
@@ -0,0 +1,314 @@
+use crate::address_space::{active_l4_frame, is_kernel_l4_slot};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::mapper::MapperFlush;
+use x86_64::structures::paging::{Page, PageSize, PhysFrame};
+use x86_64::VirtAddr;
+
+// NOTE: batched TLB invalidation
+//
+// Instead of flushing every page right after changing its entry, the pages are collected
+// in a `TlbBatch`. Up to `FLUSH_ALL_THRESHOLD` pages are invalidated one by one with `invlpg`,
+// above that reloading CR3 is cheaper than issuing all the `invlpg`s.
+pub const FLUSH_ALL_THRESHOLD: usize = 32;
+
+// NOTE: process context identifiers (PCID)
+//
+// With CR4.PCIDE set, every TLB entry is tagged with the PCID in the low 12 bits of CR3,
+// so switching to another address space does not have to throw away the entries of the
+// previous one. PCID 0 is used for every page table loaded without a PCID of its own
+// (e.g. the kernel table) and is flushed whenever it is loaded.
+//
+// `invlpg` and reloading CR3 only affect the current PCID. Entries of other PCIDs that
+// may be out of date are recorded as stale, and the next switch to a stale PCID flushes it.
+// Kernel mappings are not global, so changing one makes every other PCID stale.
+const MAX_PCID: usize = 4096;
+const CR3_NO_FLUSH: u64 = 1 << 63;
+const CR3_PCID_MASK: u64 = 0xfff;
+const CR3_ADDR_MASK: u64 = 0x_000f_ffff_ffff_f000;
+const CR4_PCIDE: u64 = 1 << 17;
+// CPUID.01H:ECX
+const CPUID_PCID: u32 = 1 << 17;
+
+static mut PCID_ENABLED: bool = false;
+
+struct PcidTable {
+    // the level 4 table owning each PCID, 0 if the PCID is free
+    owners: [u64; MAX_PCID],
+    // PCIDs that may have out of date entries
+    stale: [u64; MAX_PCID / 64],
+}
+
+impl PcidTable {
+    fn is_stale(&self, pcid: usize) -> bool {
+        self.stale[pcid / 64] & (1 << (pcid % 64)) != 0
+    }
+
+    fn set_stale(&mut self, pcid: usize, stale: bool) {
+        if stale {
+            self.stale[pcid / 64] |= 1 << (pcid % 64);
+        } else {
+            self.stale[pcid / 64] &= !(1 << (pcid % 64));
+        }
+    }
+
+    fn find(&self, l4_frame: PhysFrame) -> Option<usize> {
+        let addr = l4_frame.start_address().as_u64();
+        (1..MAX_PCID).find(|&pcid| self.owners[pcid] == addr)
+    }
+}
+
+lazy_static! {
+    // only locked with interrupts disabled, the scheduler needs it to switch processes
+    // 32KiB, far too big for the heap. it is built on the boot stack by `init`
+    static ref PCIDS: Mutex<PcidTable> = Mutex::new(PcidTable {
+        owners: [0; MAX_PCID],
+        // a PCID may have been used before we took over
+        stale: [u64::max_value(); MAX_PCID / 64],
+    });
+}
+
+unsafe fn read_cr3() -> u64 {
+    let value: u64;
+    asm!("mov $0, cr3" : "=r"(value) : : "memory" : "intel", "volatile");
+    value
+}
+
+unsafe fn write_cr3(value: u64) {
+    asm!("mov cr3, $0" : : "r"(value) : "memory" : "intel", "volatile");
+}
+
+unsafe fn read_cr4() -> u64 {
+    let value: u64;
+    asm!("mov $0, cr4" : "=r"(value) : : "memory" : "intel", "volatile");
+    value
+}
+
+unsafe fn write_cr4(value: u64) {
+    asm!("mov cr4, $0" : : "r"(value) : "memory" : "intel", "volatile");
+}
+
+// enable PCIDs if the CPU supports them
+// must be called before the first process is created
+pub fn init() {
+    let features = unsafe { core::arch::x86_64::__cpuid(1) };
+    if features.ecx & CPUID_PCID == 0 {
+        return;
+    }
+    unsafe {
+        // CR4.PCIDE may only be set while the current PCID is 0
+        let cr3 = read_cr3();
+        write_cr3(cr3 & !CR3_PCID_MASK);
+        write_cr4(read_cr4() | CR4_PCIDE);
+        PCID_ENABLED = true;
+    }
+    lazy_static::initialize(&PCIDS);
+}
+
+pub fn pcid_enabled() -> bool {
+    unsafe { PCID_ENABLED }
+}
+
+fn current_pcid() -> usize {
+    (unsafe { read_cr3() } & CR3_PCID_MASK) as usize
+}
+
+// reserve a PCID for the address space rooted at `l4_frame`
+// returns None if PCIDs are disabled or all of them are in use
+pub fn alloc_pcid(l4_frame: PhysFrame) -> Option<u16> {
+    if !pcid_enabled() {
+        return None;
+    }
+    without_interrupts(|| {
+        let mut pcids = PCIDS.lock();
+        let pcid = (1..MAX_PCID).find(|&pcid| pcids.owners[pcid] == 0)?;
+        pcids.owners[pcid] = l4_frame.start_address().as_u64();
+        // whatever the previous owner left behind is flushed on the first switch
+        pcids.set_stale(pcid, true);
+        Some(pcid as u16)
+    })
+}
+
+pub fn free_pcid(pcid: u16) {
+    without_interrupts(|| {
+        let mut pcids = PCIDS.lock();
+        pcids.owners[pcid as usize] = 0;
+        pcids.set_stale(pcid as usize, true);
+    });
+}
+
+// the CR3 value that switches to `l4_frame`
+// the TLB entries of `pcid` survive the switch unless they are stale
+pub fn cr3_for_switch(l4_frame: PhysFrame, pcid: Option<u16>) -> usize {
+    let addr = l4_frame.start_address().as_u64();
+    let pcid = match pcid {
+        Some(pcid) if pcid_enabled() => pcid as usize,
+        _ => return addr as usize,
+    };
+    without_interrupts(|| {
+        let mut pcids = PCIDS.lock();
+        let mut value = addr | pcid as u64;
+        if !pcids.is_stale(pcid) {
+            value |= CR3_NO_FLUSH;
+        }
+        pcids.set_stale(pcid, false);
+        value as usize
+    })
+}
+
+// load the address space rooted at `l4_frame`
+pub fn switch_address_space(l4_frame: PhysFrame, pcid: Option<u16>) {
+    let value = cr3_for_switch(l4_frame, pcid);
+    unsafe { write_cr3(value as u64) };
+}
+
+// the level 4 table in a CR3 value
+pub fn cr3_frame(cr3: usize) -> PhysFrame {
+    use x86_64::PhysAddr;
+
+    PhysFrame::containing_address(PhysAddr::new(cr3 as u64 & CR3_ADDR_MASK))
+}
+
+// every PCID except the current one may have out of date entries
+fn mark_others_stale() {
+    if !pcid_enabled() {
+        return;
+    }
+    let current = current_pcid();
+    without_interrupts(|| {
+        let mut pcids = PCIDS.lock();
+        for word in pcids.stale.iter_mut() {
+            *word = u64::max_value();
+        }
+        pcids.set_stale(current, false);
+    });
+}
+
+// flush the whole TLB of every address space
+// unlike `x86_64::instructions::tlb::flush_all`, the current PCID is kept
+pub fn flush_all() {
+    unsafe { write_cr3(read_cr3() & !CR3_NO_FLUSH) };
+    mark_others_stale();
+}
+
+// flush all entries of the address space rooted at `l4_frame`, it does not need to be active
+pub fn flush_address_space(l4_frame: PhysFrame) {
+    if l4_frame == active_l4_frame() {
+        unsafe { write_cr3(read_cr3() & !CR3_NO_FLUSH) };
+        return;
+    }
+    if !pcid_enabled() {
+        // without PCIDs, loading CR3 always flushes
+        return;
+    }
+    without_interrupts(|| {
+        let mut pcids = PCIDS.lock();
+        if let Some(pcid) = pcids.find(l4_frame) {
+            pcids.set_stale(pcid, true);
+        }
+    });
+}
+
+// collects the pages whose entries changed, the TLB is flushed once by `flush` or on drop
+//
+//     let mut batch = TlbBatch::new();
+//     for page in pages {
+//         let (_, flush) = mapper.unmap(page)?;
+//         batch.add_flush(page, flush);
+//     }
+//     batch.flush();
+pub struct TlbBatch {
+    l4_frame: PhysFrame,
+    pages: [VirtAddr; FLUSH_ALL_THRESHOLD],
+    len: usize,
+    // more than FLUSH_ALL_THRESHOLD pages, or `flush_all` was requested
+    full: bool,
+    // a kernel page is part of the batch, which affects every address space
+    kernel: bool,
+}
+
+impl TlbBatch {
+    // a batch for the active address space
+    pub fn new() -> Self {
+        TlbBatch::for_address_space(active_l4_frame())
+    }
+
+    pub fn for_address_space(l4_frame: PhysFrame) -> Self {
+        TlbBatch {
+            l4_frame: l4_frame,
+            pages: [VirtAddr::new(0); FLUSH_ALL_THRESHOLD],
+            len: 0,
+            full: false,
+            kernel: false,
+        }
+    }
+
+    pub fn add(&mut self, addr: VirtAddr) {
+        if is_kernel_l4_slot(usize::from(addr.p4_index())) {
+            self.kernel = true;
+        }
+        if self.len < FLUSH_ALL_THRESHOLD {
+            self.pages[self.len] = addr;
+            self.len += 1;
+        } else {
+            self.full = true;
+        }
+    }
+
+    pub fn add_page<S: PageSize>(&mut self, page: Page<S>) {
+        self.add(page.start_address());
+    }
+
+    // take over the flush returned by `Mapper::map_to` or `Mapper::unmap`
+    pub fn add_flush<S: PageSize>(&mut self, page: Page<S>, flush: MapperFlush<S>) {
+        flush.ignore();
+        self.add_page(page);
+    }
+
+    pub fn flush_all(&mut self) {
+        self.full = true;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0 && !self.full
+    }
+
+    pub fn flush(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+        if self.l4_frame != active_l4_frame() {
+            flush_address_space(self.l4_frame);
+        } else if self.full {
+            unsafe { write_cr3(read_cr3() & !CR3_NO_FLUSH) };
+        } else {
+            for addr in self.pages[..self.len].iter() {
+                tlb::flush(*addr);
+            }
+        }
+        // kernel mappings are shared, other address spaces may have them cached too
+        if self.kernel {
+            if self.l4_frame != active_l4_frame() {
+                flush_all();
+            } else {
+                mark_others_stale();
+            }
+        }
+        self.len = 0;
+        self.full = false;
+        self.kernel = false;
+    }
+}
+
+impl Drop for TlbBatch {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
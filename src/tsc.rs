@@ -0,0 +1,166 @@
+use crate::acpi;
+use crate::time::{self, PIT_FREQUENCY};
+use crate::vmalloc::{ioremap, iounmap};
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::ptr;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+// NOTE: high resolution timestamps from the time stamp counter
+//
+// The TSC counts cycles at a fixed rate on CPUs with an invariant TSC. Its rate is measured
+// once against a clock of known frequency: the HPET if ACPI describes one, channel 2 of the
+// PIT otherwise (channel 0 keeps ticking for `time`). Reading it is a single instruction,
+// cheap enough to time context switches or allocator calls.
+// Without an invariant TSC the rate may change with power states, `now` is still monotonic
+// but the nanoseconds are only an estimate.
+
+// CPUID.80000007H:EDX
+const CPUID_INVARIANT_TSC: u32 = 1 << 8;
+const CALIBRATION_MS: u64 = 10;
+
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+// channel 2, low byte then high byte, mode 0 (interrupt on terminal count), binary
+const PIT_ONE_SHOT: u8 = 0xb0;
+// bit 0: channel 2 gate, bit 1: speaker, bit 5: channel 2 output
+const PIT_GATE_PORT: u16 = 0x61;
+
+const HPET_SIZE: usize = 0x400;
+const HPET_CAPABILITIES: usize = 0x00;
+const HPET_CONFIG: usize = 0x10;
+const HPET_COUNTER: usize = 0xf0;
+const HPET_ENABLE: u64 = 1 << 0;
+// COUNT_SIZE_CAP, clear if the main counter is only 32 bits wide
+const HPET_64BIT_COUNTER: u64 = 1 << 13;
+const FEMTOS_PER_MS: u64 = 1_000_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationSource {
+    Hpet,
+    Pit,
+}
+
+// 0 until calibrated
+static mut TSC_KHZ: u64 = 0;
+// the counter value `now` counts from
+static mut TSC_BASE: u64 = 0;
+static mut SOURCE: Option<CalibrationSource> = None;
+
+pub fn cycles() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+pub fn is_invariant() -> bool {
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended < 0x8000_0007 {
+        return false;
+    }
+    unsafe { __cpuid(0x8000_0007) }.edx & CPUID_INVARIANT_TSC != 0
+}
+
+pub fn frequency_khz() -> Option<u64> {
+    match unsafe { TSC_KHZ } {
+        0 => None,
+        khz => Some(khz),
+    }
+}
+
+pub fn calibration_source() -> Option<CalibrationSource> {
+    unsafe { SOURCE }
+}
+
+pub fn cycles_to_nanos(cycles: u64) -> u64 {
+    match frequency_khz() {
+        Some(khz) => (u128::from(cycles) * 1_000_000 / u128::from(khz)) as u64,
+        None => 0,
+    }
+}
+
+// nanoseconds since `init`, falls back to the tick counter if the TSC is not calibrated
+pub fn now() -> u64 {
+    if frequency_khz().is_none() {
+        return time::Instant::now().as_nanos();
+    }
+    cycles_to_nanos(cycles() - unsafe { TSC_BASE })
+}
+
+// measure the TSC rate, needs the heap and the vmalloc area for the HPET registers
+pub fn init() -> Option<u64> {
+    let (khz, source) = match calibrate_with_hpet() {
+        Some(khz) => (khz, CalibrationSource::Hpet),
+        None => (calibrate_with_pit()?, CalibrationSource::Pit),
+    };
+    unsafe {
+        TSC_BASE = cycles();
+        TSC_KHZ = khz;
+        SOURCE = Some(source);
+    }
+    Some(khz)
+}
+
+fn calibrate_with_pit() -> Option<u64> {
+    let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+    let command: Port<u8> = Port::new(PIT_COMMAND);
+    let channel_2: Port<u8> = Port::new(PIT_CHANNEL_2);
+    let gate: Port<u8> = Port::new(PIT_GATE_PORT);
+
+    let elapsed = without_interrupts(|| unsafe {
+        let saved_gate = gate.read();
+        // gate on, speaker off
+        gate.write((saved_gate & !0x02) | 0x01);
+        command.write(PIT_ONE_SHOT);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+        let start = cycles();
+        // the output goes high once the count reached zero
+        while gate.read() & 0x20 == 0 {}
+        let end = cycles();
+        gate.write(saved_gate);
+        end - start
+    });
+    // `count` PIT cycles took `elapsed` TSC cycles
+    match elapsed * PIT_FREQUENCY / (count * 1000) {
+        0 => None,
+        khz => Some(khz),
+    }
+}
+
+fn calibrate_with_hpet() -> Option<u64> {
+    let phys_addr = acpi::find_hpet()?;
+    let base = ioremap(PhysAddr::new(phys_addr), HPET_SIZE)?;
+    let reg = |offset: usize| (base.as_u64() as usize + offset) as *mut u64;
+
+    let khz = without_interrupts(|| unsafe {
+        let capabilities = ptr::read_volatile(reg(HPET_CAPABILITIES));
+        // the counter period in femtoseconds
+        let period = capabilities >> 32;
+        if period == 0 {
+            return None;
+        }
+        // a 32 bit counter wraps around every few minutes, it may do so while we wait
+        let mask = if capabilities & HPET_64BIT_COUNTER != 0 {
+            u64::max_value()
+        } else {
+            u64::from(u32::max_value())
+        };
+        let counted = |from: u64, to: u64| to.wrapping_sub(from) & mask;
+        let config = ptr::read_volatile(reg(HPET_CONFIG));
+        ptr::write_volatile(reg(HPET_CONFIG), config | HPET_ENABLE);
+
+        let wait = CALIBRATION_MS * FEMTOS_PER_MS / period;
+        let counter_start = ptr::read_volatile(reg(HPET_COUNTER));
+        let start = cycles();
+        while counted(counter_start, ptr::read_volatile(reg(HPET_COUNTER))) < wait {}
+        let end = cycles();
+        let counter_end = ptr::read_volatile(reg(HPET_COUNTER));
+
+        ptr::write_volatile(reg(HPET_CONFIG), config);
+        let femtos = u128::from(counted(counter_start, counter_end)) * u128::from(period);
+        Some((u128::from(end - start) * u128::from(FEMTOS_PER_MS) / femtos) as u64)
+    });
+    iounmap(base);
+    khz.filter(|&khz| khz > 0)
+}
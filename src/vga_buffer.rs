@@ -42,6 +42,7 @@ struct ScreenChar {
 
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
+const BACKSPACE: u8 = 0x08;
 
 #[repr(transparent)]
 struct Buffer {
@@ -58,6 +59,7 @@ impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
+            BACKSPACE => self.backspace(),
             byte => {
                 if self.column_pos >= BUFFER_WIDTH {
                     self.new_line();
@@ -81,7 +83,7 @@ impl Writer {
             match byte {
                 // ..= is inclusive range, same as the obsolete ...
                 // print ascii bytes
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                0x20..=0x7e | b'\n' | BACKSPACE => self.write_byte(byte),
                 // not part of printable ascii range
                 _ => self.write_byte(0xfe),
             }
@@ -99,6 +101,19 @@ impl Writer {
         self.column_pos = 0;
     }
 
+    // erase the last character of the current line
+    fn backspace(&mut self) {
+        if self.column_pos == 0 {
+            return;
+        }
+        self.column_pos -= 1;
+        let blank = ScreenChar {
+            ascii_char: b' ',
+            color_code: self.color_code,
+        };
+        self.buffer.chars[BUFFER_HEIGHT - 1][self.column_pos].write(blank);
+    }
+
     fn clear_row(&mut self, row: usize) {
         let blank = ScreenChar {
             ascii_char: b' ',
@@ -248,4 +263,14 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn write_backspace() {
+        let mut writer = construct_writer();
+        writer.write_string("ab\u{8}\u{8}\u{8}c");
+        let row = &writer.buffer.chars[BUFFER_HEIGHT - 1];
+        assert_eq!(row[0].read().ascii_char, b'c');
+        assert_eq!(row[1].read().ascii_char, b' ');
+        assert_eq!(writer.column_pos, 1);
+    }
 }
@@ -0,0 +1,344 @@
+use crate::data_structures::AvlTree;
+
+use alloc::vec::Vec;
+use bitflags::bitflags;
+
+bitflags! {
+    pub struct VmaFlags: u32 {
+        const READ = 0x1;
+        const WRITE = 0x2;
+        const EXEC = 0x4;
+        const USER = 0x8;
+    }
+}
+
+// what backs the pages of an area
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaBacking {
+    // zero-filled on first touch
+    Anonymous,
+    // frames of a shared memory object, identified by its id
+    // `offset` is the offset of `start` inside the object
+    Shared { id: usize, offset: u64 },
+    // `offset` is the file offset of `start`
+    File { inode: usize, offset: u64 },
+    // never mapped, any access is an overflow
+    StackGuard,
+}
+
+// NOTE: a virtual memory area covers [start, end), both page aligned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    pub flags: VmaFlags,
+    pub backing: VmaBacking,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    Unaligned,
+    Empty,
+    Overlap,
+    NotMapped,
+}
+
+const PAGE_SIZE: u64 = 4096;
+
+impl Vma {
+    pub fn new(start: u64, end: u64, flags: VmaFlags, backing: VmaBacking) -> Self {
+        Vma {
+            start: start,
+            end: end,
+            flags: flags,
+            backing: backing,
+        }
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    // the backing of the part starting at `addr`
+    fn backing_at(&self, addr: u64) -> VmaBacking {
+        match self.backing {
+            VmaBacking::File { inode, offset } => VmaBacking::File {
+                inode: inode,
+                offset: offset + (addr - self.start),
+            },
+            VmaBacking::Shared { id, offset } => VmaBacking::Shared {
+                id: id,
+                offset: offset + (addr - self.start),
+            },
+            backing => backing,
+        }
+    }
+
+    // `next` starts where `self` ends and continues it seamlessly
+    fn can_merge(&self, next: &Vma) -> bool {
+        self.end == next.start
+            && self.flags == next.flags
+            && self.backing_at(next.start) == next.backing
+            && self.backing != VmaBacking::StackGuard
+    }
+}
+
+// NOTE: the areas of one address space, keyed by start address
+// areas never overlap
+#[derive(Default)]
+pub struct VmaSet {
+    areas: AvlTree<u64, Vma>,
+}
+
+impl VmaSet {
+    pub fn new() -> Self {
+        VmaSet {
+            areas: AvlTree::new(),
+        }
+    }
+
+    pub fn find(&self, addr: u64) -> Option<&Vma> {
+        match self.areas.floor(&addr) {
+            Some((_, vma)) if vma.contains(addr) => Some(vma),
+            _ => None,
+        }
+    }
+
+    // true if any area intersects [start, end)
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        if self.find(start).is_some() {
+            return true;
+        }
+        match self.areas.ceiling(&start) {
+            Some((next_start, _)) => next_start < end,
+            None => false,
+        }
+    }
+
+    // add a new area, adjacent compatible areas are merged with it
+    pub fn insert(&mut self, vma: Vma) -> Result<(), VmaError> {
+        if vma.start % PAGE_SIZE != 0 || vma.end % PAGE_SIZE != 0 {
+            return Err(VmaError::Unaligned);
+        }
+        if vma.start >= vma.end {
+            return Err(VmaError::Empty);
+        }
+        if self.overlaps(vma.start, vma.end) {
+            return Err(VmaError::Overlap);
+        }
+        self.areas.insert(vma.start, vma);
+        self.merge_around(vma.start);
+        Ok(())
+    }
+
+    // cut the area containing `addr` into [start, addr) and [addr, end)
+    // does nothing if `addr` is already a boundary
+    pub fn split(&mut self, addr: u64) -> Result<(), VmaError> {
+        if addr % PAGE_SIZE != 0 {
+            return Err(VmaError::Unaligned);
+        }
+        let vma = *self.find(addr).ok_or(VmaError::NotMapped)?;
+        if vma.start == addr {
+            return Ok(());
+        }
+
+        let upper = Vma::new(addr, vma.end, vma.flags, vma.backing_at(addr));
+        if let Some(lower) = self.areas.get_mut(&vma.start) {
+            lower.end = addr;
+        }
+        self.areas.insert(addr, upper);
+        Ok(())
+    }
+
+    // merge the area starting at `start` with its neighbours if they are compatible
+    pub fn merge_around(&mut self, start: u64) {
+        let mut vma = match self.areas.get(&start) {
+            Some(vma) => *vma,
+            None => return,
+        };
+
+        // with the previous area
+        let prev = match start.checked_sub(1) {
+            Some(addr) => self.find(addr).cloned(),
+            None => None,
+        };
+        if let Some(prev) = prev {
+            if prev.can_merge(&vma) {
+                self.areas.remove(&vma.start);
+                vma = Vma::new(prev.start, vma.end, prev.flags, prev.backing);
+                self.areas.insert(vma.start, vma);
+            }
+        }
+
+        // with the next area
+        let next = self.areas.get(&vma.end).cloned();
+        if let Some(next) = next {
+            if vma.can_merge(&next) {
+                self.areas.remove(&next.start);
+                if let Some(vma) = self.areas.get_mut(&vma.start) {
+                    vma.end = next.end;
+                }
+            }
+        }
+    }
+
+    // remove [start, end), areas crossing the boundaries are split first
+    // returns the removed pieces so the caller can unmap them
+    pub fn remove(&mut self, start: u64, end: u64) -> Result<Vec<Vma>, VmaError> {
+        if start % PAGE_SIZE != 0 || end % PAGE_SIZE != 0 {
+            return Err(VmaError::Unaligned);
+        }
+        if self.find(start).is_some() {
+            self.split(start)?;
+        }
+        if self.find(end).is_some() {
+            self.split(end)?;
+        }
+
+        let keys: Vec<u64> = self
+            .areas
+            .iter()
+            .filter(|(key, _)| *key >= start && *key < end)
+            .map(|(key, _)| key)
+            .collect();
+        let mut removed = Vec::new();
+        for key in keys {
+            if let Some(vma) = self.areas.remove(&key) {
+                removed.push(vma);
+            }
+        }
+        Ok(removed)
+    }
+
+    // change the flags of [start, end), which must be fully covered
+    pub fn protect(&mut self, start: u64, end: u64, flags: VmaFlags) -> Result<(), VmaError> {
+        let mut addr = start;
+        while addr < end {
+            let vma = self.find(addr).ok_or(VmaError::NotMapped)?;
+            addr = vma.end;
+        }
+        self.split(start)?;
+        if self.find(end).is_some() {
+            self.split(end)?;
+        }
+
+        let keys: Vec<u64> = self
+            .areas
+            .iter()
+            .filter(|(key, _)| *key >= start && *key < end)
+            .map(|(key, _)| key)
+            .collect();
+        for &key in keys.iter() {
+            if let Some(vma) = self.areas.get_mut(&key) {
+                vma.flags = flags;
+            }
+        }
+        for key in keys {
+            if self.areas.get(&key).is_some() {
+                self.merge_around(key);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.iter().map(|(_, vma)| vma)
+    }
+
+    pub fn len(&self) -> usize {
+        self.areas.size()
+    }
+}
+
+impl Clone for VmaSet {
+    fn clone(&self) -> Self {
+        let mut set = VmaSet::new();
+        for vma in self.iter() {
+            set.areas.insert(vma.start, *vma);
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rw() -> VmaFlags {
+        VmaFlags::READ | VmaFlags::WRITE
+    }
+
+    fn anon(start: u64, end: u64) -> Vma {
+        Vma::new(start, end, rw(), VmaBacking::Anonymous)
+    }
+
+    #[test]
+    fn insert_and_find() {
+        let mut set = VmaSet::new();
+        set.insert(anon(0x1000, 0x3000)).unwrap();
+        set.insert(anon(0x5000, 0x6000)).unwrap();
+        assert_eq!(set.insert(anon(0x2000, 0x5000)), Err(VmaError::Overlap));
+        assert_eq!(set.insert(anon(0x0, 0x2000)), Err(VmaError::Overlap));
+        assert_eq!(set.find(0x2fff).map(|v| v.start), Some(0x1000));
+        assert!(set.find(0x3000).is_none());
+        assert!(set.find(0x4fff).is_none());
+    }
+
+    #[test]
+    fn adjacent_areas_merge() {
+        let mut set = VmaSet::new();
+        set.insert(anon(0x1000, 0x2000)).unwrap();
+        set.insert(anon(0x3000, 0x4000)).unwrap();
+        set.insert(anon(0x2000, 0x3000)).unwrap();
+        assert_eq!(set.len(), 1);
+        assert_eq!(set.find(0x1000).unwrap().end, 0x4000);
+
+        // a guard page never merges
+        let guard = Vma::new(0x4000, 0x5000, VmaFlags::empty(), VmaBacking::StackGuard);
+        set.insert(guard).unwrap();
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn split_and_protect() {
+        let mut set = VmaSet::new();
+        set.insert(Vma::new(
+            0x10000,
+            0x20000,
+            rw(),
+            VmaBacking::File { inode: 3, offset: 0 },
+        ))
+        .unwrap();
+
+        set.protect(0x14000, 0x18000, VmaFlags::READ).unwrap();
+        assert_eq!(set.len(), 3);
+        let middle = set.find(0x15000).unwrap();
+        assert_eq!(middle.flags, VmaFlags::READ);
+        assert_eq!(
+            middle.backing,
+            VmaBacking::File {
+                inode: 3,
+                offset: 0x4000
+            }
+        );
+
+        // restoring the flags merges the pieces back
+        set.protect(0x14000, 0x18000, rw()).unwrap();
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    fn remove_range() {
+        let mut set = VmaSet::new();
+        set.insert(anon(0x1000, 0x9000)).unwrap();
+        let removed = set.remove(0x3000, 0x5000).unwrap();
+        assert_eq!(removed, vec![anon(0x3000, 0x5000)]);
+        assert_eq!(set.len(), 2);
+        assert!(set.find(0x4000).is_none());
+        assert_eq!(set.find(0x5000).unwrap().end, 0x9000);
+    }
+}
@@ -0,0 +1,200 @@
+use crate::data_structures::AvlTree;
+use crate::frame_allocator::get_frame_allocator;
+use crate::memory;
+use crate::tlb::TlbBatch;
+use crate::PHYSICAL_MEMORY_OFFSET;
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::structures::paging::{
+    FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// NOTE: virtually contiguous kernel allocations
+//
+// `vmalloc` backs a range of the vmalloc area with single frames, so a large buffer does not
+// need a physically contiguous buddy block. Every allocation is followed by an unmapped
+// guard page, running past the end of a buffer faults instead of corrupting the next one:
+//
+//   | buffer ... buffer | guard | buffer ... | guard | ...
+//
+// `ioremap` uses the same area to map device memory (e.g. APIC registers) uncached,
+// those frames belong to the device and are never given to the frame allocator.
+pub const VMALLOC_START: u64 = 0x_ffff_b000_0000_0000;
+pub const VMALLOC_END: u64 = 0x_ffff_b100_0000_0000;
+const GUARD_PAGES: u64 = 1;
+
+#[derive(Debug, Clone, Copy)]
+struct VmallocArea {
+    // number of mapped pages, the guard pages come after them
+    page_num: u64,
+    // device memory mapped by `ioremap`
+    mmio: bool,
+}
+
+impl VmallocArea {
+    fn end(&self, start: u64) -> u64 {
+        start + (self.page_num + GUARD_PAGES) * Size4KiB::SIZE
+    }
+}
+
+lazy_static! {
+    // allocated areas keyed by start address
+    static ref VMALLOC_AREAS: Mutex<AvlTree<u64, VmallocArea>> = Mutex::new(AvlTree::new());
+}
+
+// first fit: the lowest gap that can hold `page_num` pages plus the guard
+fn find_free_range(areas: &AvlTree<u64, VmallocArea>, page_num: u64) -> Option<u64> {
+    let size = (page_num + GUARD_PAGES) * Size4KiB::SIZE;
+    let mut candidate = VMALLOC_START;
+    for (start, area) in areas.iter() {
+        if candidate + size <= start {
+            break;
+        }
+        candidate = area.end(start);
+    }
+    if candidate + size > VMALLOC_END {
+        return None;
+    }
+    Some(candidate)
+}
+
+// allocate `size` bytes (rounded up to whole pages) of zeroed kernel memory
+pub fn vmalloc(size: usize) -> Option<VirtAddr> {
+    if size == 0 {
+        return None;
+    }
+    let page_num = (size as u64 + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+    // reserve the range before mapping, the frame allocator may be slow
+    let start = reserve_range(page_num, false)?;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let frame_allocator = get_frame_allocator();
+    let mut mapper = unsafe { memory::init(PHYSICAL_MEMORY_OFFSET as u64) };
+    for i in 0..page_num {
+        let page: Page<Size4KiB> = page_at(start, i);
+        let frame = match frame_allocator.alloc_zeroed_frame() {
+            Some(frame) => frame,
+            None => {
+                unmap_pages(start, i, true);
+                VMALLOC_AREAS.lock().remove(&start);
+                return None;
+            }
+        };
+        let map_to_result = unsafe { mapper.map_to(page, frame, flags, frame_allocator) };
+        match map_to_result {
+            // the page was not mapped before, so the TLB has nothing to forget
+            Ok(flush) => flush.ignore(),
+            Err(_) => {
+                frame_allocator.deallocate_frame(frame);
+                unmap_pages(start, i, true);
+                VMALLOC_AREAS.lock().remove(&start);
+                return None;
+            }
+        }
+        if let Some(frame_info) = frame_allocator.frame_info(frame) {
+            frame_info.inc_count();
+        }
+    }
+    Some(VirtAddr::new(start))
+}
+
+// free a buffer returned by `vmalloc`
+pub fn vfree(addr: VirtAddr) {
+    let area = VMALLOC_AREAS.lock().remove(&addr.as_u64());
+    match area {
+        Some(area) if !area.mmio => unmap_pages(addr.as_u64(), area.page_num, true),
+        _ => panic!("vfree of {:?}, which is not a vmalloc allocation", addr),
+    }
+}
+
+// map `size` bytes of device memory at `phys_addr` uncached
+// returns the virtual address corresponding to `phys_addr`
+pub fn ioremap(phys_addr: PhysAddr, size: usize) -> Option<VirtAddr> {
+    if size == 0 {
+        return None;
+    }
+    let first_frame: PhysFrame<Size4KiB> = PhysFrame::containing_address(phys_addr);
+    let offset = phys_addr.as_u64() - first_frame.start_address().as_u64();
+    let page_num = (offset + size as u64 + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+    let start = reserve_range(page_num, true)?;
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    let frame_allocator = get_frame_allocator();
+    let mut mapper = unsafe { memory::init(PHYSICAL_MEMORY_OFFSET as u64) };
+    for i in 0..page_num {
+        let page: Page<Size4KiB> = page_at(start, i);
+        let frame = first_frame + i;
+        // the allocator is only needed for missing page tables
+        let map_to_result = unsafe { mapper.map_to(page, frame, flags, frame_allocator) };
+        match map_to_result {
+            Ok(flush) => flush.ignore(),
+            Err(_) => {
+                unmap_pages(start, i, false);
+                VMALLOC_AREAS.lock().remove(&start);
+                return None;
+            }
+        }
+    }
+    Some(VirtAddr::new(start + offset))
+}
+
+// undo an `ioremap`, `addr` is the address it returned
+pub fn iounmap(addr: VirtAddr) {
+    let start = addr.align_down(Size4KiB::SIZE).as_u64();
+    let area = VMALLOC_AREAS.lock().remove(&start);
+    match area {
+        Some(area) if area.mmio => unmap_pages(start, area.page_num, false),
+        _ => panic!("iounmap of {:?}, which is not an ioremap mapping", addr),
+    }
+}
+
+// the size in bytes of the buffer at `addr`
+pub fn vmalloc_size(addr: VirtAddr) -> Option<usize> {
+    let areas = VMALLOC_AREAS.lock();
+    let area = areas.get(&addr.as_u64())?;
+    Some((area.page_num * Size4KiB::SIZE) as usize)
+}
+
+fn reserve_range(page_num: u64, mmio: bool) -> Option<u64> {
+    let mut areas = VMALLOC_AREAS.lock();
+    let start = find_free_range(&areas, page_num)?;
+    areas.insert(
+        start,
+        VmallocArea {
+            page_num: page_num,
+            mmio: mmio,
+        },
+    );
+    Some(start)
+}
+
+fn page_at(start: u64, idx: u64) -> Page<Size4KiB> {
+    Page::containing_address(VirtAddr::new(start + idx * Size4KiB::SIZE))
+}
+
+// the frames are only released for vmalloc areas, device memory is not ours to free
+fn unmap_pages(start: u64, page_num: u64, release_frames: bool) {
+    let frame_allocator = get_frame_allocator();
+    let mut mapper = unsafe { memory::init(PHYSICAL_MEMORY_OFFSET as u64) };
+    let mut batch = TlbBatch::new();
+    for i in 0..page_num {
+        let page: Page<Size4KiB> = page_at(start, i);
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            batch.add_flush(page, flush);
+            if !release_frames {
+                continue;
+            }
+            if let Some(frame_info) = frame_allocator.frame_info(frame) {
+                frame_info.dec_count();
+            }
+            frame_allocator.deallocate_frame(frame);
+        }
+    }
+    batch.flush();
+}